@@ -0,0 +1,513 @@
+use std::{
+    f64::consts::PI,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use clap::ValueEnum;
+use log::{debug, error, info};
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum Experiment {
+    /// Oscillate the fan between two outputs around the current temperature (Åström–Hägglund)
+    Relay,
+    /// Apply a single output step and fit a first order model with dead time
+    Step,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum TuningRule {
+    ZieglerNichols,
+    /// More conservative variant of Ziegler–Nichols
+    TyreusLuyben,
+    /// Requires a step experiment
+    CohenCoon,
+    /// Skogestad IMC. Requires a step experiment
+    Simc,
+}
+
+/// Interface to the controlled fan and observed sensor
+pub trait Process {
+    /// Sets the fan output in percent
+    fn set_output(&mut self, percentage: f64);
+    /// Reads the current value of the sensor
+    fn read(&mut self) -> f64;
+    /// Waits for one sample interval
+    fn wait(&mut self);
+}
+
+pub struct AutotuneConfig {
+    pub experiment: Experiment,
+    pub rule: TuningRule,
+    /// Lower output in percent
+    pub low: f64,
+    /// Upper output in percent
+    pub high: f64,
+    /// Hysteresis of the relay around the setpoint
+    pub hysteresis: f64,
+    /// Number of relay oscillations to analyze
+    pub cycles: usize,
+    /// Time between two samples
+    pub interval: Duration,
+    /// Time after which the experiment is aborted
+    pub timeout: Duration,
+    /// Maximum change over `settle_window` for the sensor to count as settled in a step experiment
+    pub settle_tolerance: f64,
+    pub settle_window: Duration,
+}
+
+/// Result of a relay experiment
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UltimateParameters {
+    pub gain: f64,
+    /// Period in seconds
+    pub period: f64,
+}
+
+/// First order plus dead time model from a step experiment
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FirstOrderModel {
+    /// Absolute change of the sensor per percent of output
+    pub gain: f64,
+    /// Time constant in seconds
+    pub time_constant: f64,
+    /// Dead time in seconds
+    pub dead_time: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Model {
+    Ultimate(UltimateParameters),
+    FirstOrder(FirstOrderModel),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Gains {
+    pub p: f64,
+    pub i: f64,
+    pub d: f64,
+}
+
+impl Gains {
+    /// Converts the gain, integral and derivative time into the gains used by the PID curve
+    fn from_times(kp: f64, ti: f64, td: f64) -> Self {
+        Self {
+            p: kp,
+            i: if ti > 0.0 { kp / ti } else { 0.0 },
+            d: kp * td,
+        }
+    }
+}
+
+/// Samples in (seconds, value) recorded during an experiment
+type Samples = Vec<(f64, f64)>;
+
+struct Recorder<'a> {
+    process: &'a mut dyn Process,
+    config: &'a AutotuneConfig,
+    stop_signal: Arc<AtomicBool>,
+    samples: Samples,
+    time: f64,
+}
+
+impl Recorder<'_> {
+    /// Waits for the next sample and records it. Returns None on stop or timeout
+    fn sample(&mut self) -> Option<f64> {
+        if !self.stop_signal.load(Ordering::SeqCst) {
+            debug!("Stop signal received. Stopping autotune");
+            return None;
+        }
+        if self.time > self.config.timeout.as_secs_f64() {
+            error!("Autotune timed out after {} seconds", self.time);
+            return None;
+        }
+        if !self.samples.is_empty() {
+            self.process.wait();
+            self.time += self.config.interval.as_secs_f64();
+        }
+        let val = self.process.read();
+        debug!("Autotune sample {:.1}s: {val}", self.time);
+        self.samples.push((self.time, val));
+        Some(val)
+    }
+
+    /// Records samples until the value changed less than the tolerance over the settle window
+    fn settle(&mut self) -> Option<f64> {
+        let window = self.config.settle_window.as_secs_f64();
+        let start = self.time;
+        loop {
+            let val = self.sample()?;
+            if self.time - start < window {
+                continue;
+            }
+            let recent = self
+                .samples
+                .iter()
+                .rev()
+                .take_while(|(time, _)| self.time - time <= window)
+                .map(|(_, val)| *val);
+            let (min, max) = recent.fold((f64::INFINITY, f64::NEG_INFINITY), |acc, val| {
+                (acc.0.min(val), acc.1.max(val))
+            });
+            if max - min <= self.config.settle_tolerance {
+                return Some(val);
+            }
+        }
+    }
+}
+
+/// Runs the configured experiment and returns the identified model.
+/// The process is left at the high output afterwards
+pub fn run_experiment(
+    process: &mut dyn Process,
+    config: &AutotuneConfig,
+    stop_signal: Arc<AtomicBool>,
+) -> Option<Model> {
+    let mut recorder = Recorder {
+        process,
+        config,
+        stop_signal,
+        samples: vec![],
+        time: 0.0,
+    };
+    let model = match config.experiment {
+        Experiment::Relay => relay_experiment(&mut recorder).map(Model::Ultimate),
+        Experiment::Step => step_experiment(&mut recorder).map(Model::FirstOrder),
+    };
+    recorder.process.set_output(config.high);
+    model
+}
+
+fn relay_experiment(recorder: &mut Recorder) -> Option<UltimateParameters> {
+    let (low, high, hysteresis) = (
+        recorder.config.low,
+        recorder.config.high,
+        recorder.config.hysteresis,
+    );
+    let setpoint = recorder.sample()?;
+    info!("Starting relay experiment around {setpoint}");
+    // More cooling lowers the value, so switch to high output once it rises above the setpoint
+    let mut output_high = true;
+    recorder.process.set_output(high);
+    // Times at which the output switched to high
+    let mut rising: Vec<f64> = vec![];
+    // Skip the first cycle as it is still influenced by the initial state
+    while rising.len() < recorder.config.cycles + 2 {
+        let val = recorder.sample()?;
+        if output_high && val < setpoint - hysteresis {
+            output_high = false;
+            recorder.process.set_output(low);
+        } else if !output_high && val > setpoint + hysteresis {
+            output_high = true;
+            recorder.process.set_output(high);
+            rising.push(recorder.time);
+            debug!("Relay switched to high at {}", recorder.time);
+        }
+    }
+    analyze_relay(
+        &recorder.samples,
+        &rising[1..],
+        (high - low) / 2.0,
+        hysteresis,
+    )
+}
+
+/// Calculates the ultimate gain and period from the oscillation between the given rising edges
+fn analyze_relay(
+    samples: &[(f64, f64)],
+    rising: &[f64],
+    amplitude: f64,
+    hysteresis: f64,
+) -> Option<UltimateParameters> {
+    if rising.len() < 2 {
+        return None;
+    }
+    let (first, last) = (rising[0], rising[rising.len() - 1]);
+    let (min, max) = samples
+        .iter()
+        .filter(|(time, _)| *time >= first && *time <= last)
+        .fold((f64::INFINITY, f64::NEG_INFINITY), |acc, (_, val)| {
+            (acc.0.min(*val), acc.1.max(*val))
+        });
+    let oscillation = (max - min) / 2.0;
+    if oscillation <= 0.0 {
+        error!("Sensor did not oscillate during relay experiment");
+        return None;
+    }
+    let period = (last - first) / (rising.len() - 1) as f64;
+    // Account for the hysteresis of the relay if possible
+    let effective = if oscillation > hysteresis {
+        (oscillation.powi(2) - hysteresis.powi(2)).sqrt()
+    } else {
+        oscillation
+    };
+    let gain = 4.0 * amplitude / (PI * effective);
+    info!("Relay experiment: oscillation {oscillation}, ultimate gain {gain}, period {period}s");
+    Some(UltimateParameters { gain, period })
+}
+
+fn step_experiment(recorder: &mut Recorder) -> Option<FirstOrderModel> {
+    let (low, high) = (recorder.config.low, recorder.config.high);
+    recorder.process.set_output(low);
+    info!("Waiting for sensor to settle at {low}%");
+    let initial = recorder.settle()?;
+    let step_time = recorder.time;
+    let step_index = recorder.samples.len();
+    info!("Settled at {initial}. Stepping output to {high}%");
+    recorder.process.set_output(high);
+    let last = recorder.settle()?;
+    info!("Settled at {last} after the step");
+    let response: Vec<(f64, f64)> = recorder.samples[step_index..]
+        .iter()
+        .map(|(time, val)| (time - step_time, *val))
+        .collect();
+    analyze_step(&response, initial, last, high - low)
+}
+
+/// Fits a first order model with dead time using the 28.3% and 63.2% points of the response
+fn analyze_step(
+    response: &[(f64, f64)],
+    initial: f64,
+    last: f64,
+    step: f64,
+) -> Option<FirstOrderModel> {
+    let change = last - initial;
+    if change.abs() < f64::EPSILON || step.abs() < f64::EPSILON {
+        error!("Sensor did not respond to the step");
+        return None;
+    }
+    let time_at = |fraction: f64| {
+        response
+            .iter()
+            .find(|(_, val)| (val - initial) / change >= fraction)
+            .map(|(time, _)| *time)
+    };
+    let t28 = time_at(0.283)?;
+    let t63 = time_at(0.632)?;
+    let time_constant = (1.5 * (t63 - t28)).max(f64::EPSILON);
+    let dead_time = (t63 - time_constant).max(0.0);
+    let model = FirstOrderModel {
+        gain: (change / step).abs(),
+        time_constant,
+        dead_time,
+    };
+    info!("Step experiment: {:?}", model);
+    Some(model)
+}
+
+/// Derives PID gains for the model using the given rule
+pub fn suggest_gains(model: &Model, rule: TuningRule) -> Option<Gains> {
+    let gains = match (model, rule) {
+        (Model::Ultimate(ultimate), TuningRule::ZieglerNichols) => Gains::from_times(
+            0.6 * ultimate.gain,
+            ultimate.period / 2.0,
+            ultimate.period / 8.0,
+        ),
+        (Model::Ultimate(ultimate), TuningRule::TyreusLuyben) => Gains::from_times(
+            ultimate.gain / 2.2,
+            2.2 * ultimate.period,
+            ultimate.period / 6.3,
+        ),
+        (Model::FirstOrder(model), TuningRule::ZieglerNichols) => {
+            // Avoid infinite gains for processes without measurable dead time
+            let dead_time = model.dead_time.max(f64::EPSILON);
+            Gains::from_times(
+                1.2 * model.time_constant / (model.gain * dead_time),
+                2.0 * dead_time,
+                0.5 * dead_time,
+            )
+        }
+        (Model::FirstOrder(model), TuningRule::TyreusLuyben) => {
+            let dead_time = model.dead_time.max(f64::EPSILON);
+            let ultimate = UltimateParameters {
+                // Approximation of the ultimate parameters of a first order model
+                gain: (PI * model.time_constant) / (2.0 * model.gain * dead_time),
+                period: 4.0 * dead_time,
+            };
+            return suggest_gains(&Model::Ultimate(ultimate), rule);
+        }
+        (Model::FirstOrder(model), TuningRule::CohenCoon) => {
+            let dead_time = model.dead_time.max(f64::EPSILON);
+            let ratio = dead_time / model.time_constant;
+            Gains::from_times(
+                (1.0 / model.gain) * (1.0 / ratio) * (4.0 / 3.0 + ratio / 4.0),
+                dead_time * (32.0 + 6.0 * ratio) / (13.0 + 8.0 * ratio),
+                4.0 * dead_time / (11.0 + 2.0 * ratio),
+            )
+        }
+        (Model::FirstOrder(model), TuningRule::Simc) => {
+            // Closed loop time constant equal to the dead time for a good tradeoff
+            let tau_c = model.dead_time.max(f64::EPSILON);
+            let total = tau_c + model.dead_time;
+            Gains::from_times(
+                model.time_constant / (model.gain * total),
+                model.time_constant.min(4.0 * total),
+                0.0,
+            )
+        }
+        (Model::Ultimate(_), TuningRule::CohenCoon | TuningRule::Simc) => {
+            error!("Tuning rule {rule:?} requires a step experiment");
+            return None;
+        }
+    };
+    Some(gains)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::VecDeque;
+
+    use more_asserts::{assert_ge, assert_gt, assert_le, assert_lt};
+
+    use super::*;
+
+    /// Simulated first order system with dead time where more output lowers the value
+    struct SimulatedProcess {
+        value: f64,
+        ambient: f64,
+        gain: f64,
+        time_constant: f64,
+        commanded: f64,
+        /// Outputs that did not reach the system yet
+        delay: VecDeque<f64>,
+    }
+
+    impl SimulatedProcess {
+        fn new(gain: f64, time_constant: f64, dead_time: f64, output: f64) -> Self {
+            let ambient = 80.0;
+            Self {
+                value: ambient - gain * output,
+                ambient,
+                gain,
+                time_constant,
+                commanded: output,
+                delay: VecDeque::from(vec![output; dead_time as usize]),
+            }
+        }
+    }
+
+    impl Process for SimulatedProcess {
+        fn set_output(&mut self, percentage: f64) {
+            self.commanded = percentage;
+        }
+
+        fn read(&mut self) -> f64 {
+            self.value
+        }
+
+        /// Advances the simulation by one second
+        fn wait(&mut self) {
+            self.delay.push_back(self.commanded);
+            let output = self.delay.pop_front().unwrap();
+            let target = self.ambient - self.gain * output;
+            self.value += (target - self.value) / self.time_constant;
+        }
+    }
+
+    fn config(experiment: Experiment) -> AutotuneConfig {
+        AutotuneConfig {
+            experiment,
+            rule: TuningRule::ZieglerNichols,
+            low: 20.0,
+            high: 80.0,
+            hysteresis: 0.2,
+            cycles: 3,
+            interval: Duration::from_secs(1),
+            timeout: Duration::from_secs(10000),
+            settle_tolerance: 0.01,
+            settle_window: Duration::from_secs(30),
+        }
+    }
+
+    #[test]
+    fn test_relay_experiment() {
+        let mut process = SimulatedProcess::new(0.5, 30.0, 5.0, 50.0);
+        let model = run_experiment(
+            &mut process,
+            &config(Experiment::Relay),
+            Arc::new(AtomicBool::new(true)),
+        )
+        .unwrap();
+        let Model::Ultimate(ultimate) = model else {
+            panic!("Expected ultimate parameters");
+        };
+        // Theoretical values are a period of ~20s and a gain of ~19.6
+        assert_gt!(ultimate.period, 15.0);
+        assert_lt!(ultimate.period, 30.0);
+        assert_gt!(ultimate.gain, 10.0);
+        assert_lt!(ultimate.gain, 30.0);
+
+        let gains = suggest_gains(&model, TuningRule::ZieglerNichols).unwrap();
+        assert_eq!(gains.p, 0.6 * ultimate.gain);
+        assert_eq!(gains.i, gains.p / (ultimate.period / 2.0));
+        assert_eq!(gains.d, gains.p * ultimate.period / 8.0);
+        assert!(suggest_gains(&model, TuningRule::Simc).is_none());
+        assert!(suggest_gains(&model, TuningRule::CohenCoon).is_none());
+    }
+
+    #[test]
+    fn test_step_experiment() {
+        let mut process = SimulatedProcess::new(0.5, 30.0, 5.0, 20.0);
+        let model = run_experiment(
+            &mut process,
+            &config(Experiment::Step),
+            Arc::new(AtomicBool::new(true)),
+        )
+        .unwrap();
+        let Model::FirstOrder(first_order) = model else {
+            panic!("Expected first order model");
+        };
+        assert_gt!(first_order.gain, 0.45);
+        assert_lt!(first_order.gain, 0.55);
+        assert_gt!(first_order.time_constant, 20.0);
+        assert_lt!(first_order.time_constant, 40.0);
+        assert_ge!(first_order.dead_time, 3.0);
+        assert_le!(first_order.dead_time, 10.0);
+
+        for rule in [
+            TuningRule::ZieglerNichols,
+            TuningRule::TyreusLuyben,
+            TuningRule::CohenCoon,
+            TuningRule::Simc,
+        ] {
+            let gains = suggest_gains(&model, rule).unwrap();
+            assert_gt!(gains.p, 0.0);
+            assert_gt!(gains.i, 0.0);
+            assert_ge!(gains.d, 0.0);
+        }
+    }
+
+    #[test]
+    fn test_simc_gains() {
+        let model = Model::FirstOrder(FirstOrderModel {
+            gain: 0.5,
+            time_constant: 30.0,
+            dead_time: 5.0,
+        });
+        let gains = suggest_gains(&model, TuningRule::Simc).unwrap();
+        assert_eq!(gains.p, 6.0);
+        assert_eq!(gains.i, 6.0 / 30.0);
+        assert_eq!(gains.d, 0.0);
+    }
+
+    #[test]
+    fn test_autotune_stop() {
+        let mut process = SimulatedProcess::new(0.5, 30.0, 5.0, 50.0);
+        let model = run_experiment(
+            &mut process,
+            &config(Experiment::Relay),
+            Arc::new(AtomicBool::new(false)),
+        );
+        assert!(model.is_none());
+
+        // A process that never oscillates runs into the timeout
+        let mut process = SimulatedProcess::new(0.0, 30.0, 5.0, 50.0);
+        let mut conf = config(Experiment::Relay);
+        conf.timeout = Duration::from_secs(100);
+        let model = run_experiment(&mut process, &conf, Arc::new(AtomicBool::new(true)));
+        assert!(model.is_none());
+    }
+}
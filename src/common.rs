@@ -1,6 +1,9 @@
 use std::sync::{Arc, Mutex};
 
-#[derive(PartialEq, PartialOrd, Clone, Copy, Debug)]
+use serde::{Deserialize, Serialize};
+
+#[derive(PartialEq, Eq, PartialOrd, Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum SensorType {
     TEMPERATURE,
     PERCENTAGE,
@@ -41,7 +44,7 @@ impl PartialOrd for SensorValue {
     }
 }
 
-pub trait ReadableValue {
+pub trait ReadableValue: Send {
     fn get_value(&self) -> SensorValue;
     fn update_value(&mut self) {}
 }
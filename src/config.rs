@@ -1,14 +1,47 @@
 use std::{
     collections::{BTreeMap, HashMap, HashSet},
-    vec,
+    fmt, vec,
 };
 
-use serde::{Deserialize, Serialize};
+use log::debug;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{common, expression::Expression};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct HwmonConfig {
     pub chip: String,
+    /// Label of the fan input or name of the pwm, e.g. `pwm3`, for fans without tach
     pub name: String,
+    /// Pwm output driving a fan, e.g. `pwm2`. Defaults to the pwm with the index of the fan
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pwm: Option<String>,
+}
+
+/// Relation between the output and the measured value
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[allow(non_camel_case_types)]
+pub enum PidDirection {
+    /// Output rises if the measurement is above the target
+    #[default]
+    cooling,
+    /// Output rises if the measurement is below the target
+    heating,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[allow(non_camel_case_types)]
+pub enum AntiWindup {
+    none,
+    /// Stop integrating while the output is saturated in the direction of the error
+    #[default]
+    clamping,
+    /// Feed the difference between the saturated and unsaturated output back into the integrator
+    back_calculation,
+}
+
+fn default_output_max() -> f32 {
+    100.0
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -18,12 +51,68 @@ pub struct PidCurve {
     pub p: f32,
     pub i: f32,
     pub d: f32,
+    #[serde(default)]
+    pub direction: PidDirection,
+    #[serde(default)]
+    pub output_min: f32,
+    #[serde(default = "default_output_max")]
+    pub output_max: f32,
+    /// Limits of the individual terms. No limit if not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub p_limit: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub i_limit: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub d_limit: Option<f32>,
+    #[serde(default)]
+    pub anti_windup: AntiWindup,
+    /// Gain of the back calculation. Defaults to i / p
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tracking_gain: Option<f32>,
+    /// Time constant in seconds of the low pass filter on the derivative term
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub derivative_filter: Option<f32>,
+    /// Minimum time in seconds between two updates of the controller
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sample_time: Option<f32>,
+    /// Constant added to the output
+    #[serde(default)]
+    pub feed_forward: f32,
+}
+
+impl Default for PidCurve {
+    fn default() -> Self {
+        Self {
+            sensor: String::new(),
+            target: 0.0,
+            p: 0.0,
+            i: 0.0,
+            d: 0.0,
+            direction: PidDirection::default(),
+            output_min: 0.0,
+            output_max: default_output_max(),
+            p_limit: None,
+            i_limit: None,
+            d_limit: None,
+            anti_windup: AntiWindup::default(),
+            tracking_gain: None,
+            derivative_filter: None,
+            sample_time: None,
+            feed_forward: 0.0,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct LinearCurve {
     pub sensor: String,
     pub steps: BTreeMap<i32, i32>,
+    /// Type of the sensor the steps refer to. Defaults to the type of the sensor
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub input: Option<common::SensorType>,
+    /// Type of the steps' output, e.g. rpm for fans in rpm mode. Defaults to percentage
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output: Option<common::SensorType>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -31,14 +120,226 @@ pub struct StaticCurve {
     pub value: i32,
 }
 
+/// Reference to a sensor or curve by id or an inline curve definition
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum CurveRef {
+    Id(String),
+    Inline(Box<CurveFunction>),
+}
+
+impl CurveRef {
+    /// Id of the reference. Inline curves only get an id by [RufacoConfig::flatten_inline_curves]
+    pub fn id(&self) -> &str {
+        match self {
+            CurveRef::Id(id) => id,
+            CurveRef::Inline(_) => "<inline curve>",
+        }
+    }
+
+    /// Moves an inline curve into `curves` with the given id and references it instead
+    fn flatten(&mut self, id: String, curves: &mut Vec<FanCurve>) {
+        if let CurveRef::Inline(function) = self {
+            let mut function = (**function).clone();
+            function.flatten_inline_curves(&id, curves);
+            curves.push(FanCurve {
+                id: id.clone(),
+                function,
+            });
+            *self = CurveRef::Id(id);
+        }
+    }
+}
+
+impl From<&str> for CurveRef {
+    fn from(id: &str) -> Self {
+        CurveRef::Id(id.to_string())
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct MaximumCurve {
-    pub sensors: Vec<String>,
+    pub sensors: Vec<CurveRef>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct AverageCurve {
-    pub sensors: Vec<String>,
+    pub sensors: Vec<CurveRef>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[allow(non_camel_case_types)]
+pub enum Weekday {
+    mon,
+    tue,
+    wed,
+    thu,
+    fri,
+    sat,
+    sun,
+}
+
+impl Weekday {
+    pub fn previous(&self) -> Self {
+        match self {
+            Weekday::mon => Weekday::sun,
+            Weekday::tue => Weekday::mon,
+            Weekday::wed => Weekday::tue,
+            Weekday::thu => Weekday::wed,
+            Weekday::fri => Weekday::thu,
+            Weekday::sat => Weekday::fri,
+            Weekday::sun => Weekday::sat,
+        }
+    }
+}
+
+/// Minutes since midnight. Written as `HH:MM` in the config
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TimeOfDay(pub u32);
+
+impl fmt::Display for TimeOfDay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02}:{:02}", self.0 / 60, self.0 % 60)
+    }
+}
+
+impl Serialize for TimeOfDay {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for TimeOfDay {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let time = String::deserialize(deserializer)?;
+        let parsed = time.split_once(':').and_then(|(hours, minutes)| {
+            Some((hours.parse::<u32>().ok()?, minutes.parse::<u32>().ok()?))
+        });
+        match parsed {
+            Some((hours, minutes)) if hours < 24 && minutes < 60 => {
+                Ok(TimeOfDay(hours * 60 + minutes))
+            }
+            _ => Err(de::Error::custom(format!(
+                "invalid time of day `{time}`. Expected HH:MM"
+            ))),
+        }
+    }
+}
+
+/// Output selected by a schedule. Either a static value or the id of a curve
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum ScheduleTarget {
+    Value(i32),
+    Curve(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ScheduleRule {
+    /// Days the rule starts on. Every day if empty
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+    pub from: TimeOfDay,
+    /// End of the rule. Rules wrap around midnight if `to` is before `from`. Must differ from `from`
+    pub to: TimeOfDay,
+    pub target: ScheduleTarget,
+}
+
+impl ScheduleRule {
+    pub fn is_active(&self, weekday: Weekday, time: TimeOfDay) -> bool {
+        let on_day = |day: Weekday| self.days.is_empty() || self.days.contains(&day);
+        if self.from <= self.to {
+            on_day(weekday) && self.from <= time && time < self.to
+        } else {
+            // The part after midnight belongs to the previous day
+            (on_day(weekday) && time >= self.from) || (on_day(weekday.previous()) && time < self.to)
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ScheduleCurve {
+    /// Rules are checked in order. The first active rule is used
+    pub rules: Vec<ScheduleRule>,
+    pub default: ScheduleTarget,
+    /// Time in seconds to blend between the outputs when the active rule changes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub crossfade: Option<f64>,
+}
+
+impl ScheduleCurve {
+    fn targets(&self) -> impl Iterator<Item = &ScheduleTarget> {
+        self.rules
+            .iter()
+            .map(|rule| &rule.target)
+            .chain(std::iter::once(&self.default))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SwitchCondition {
+    pub sensor: String,
+    /// Condition is met if the sensor is above this value
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub above: Option<f64>,
+    /// Condition is met if the sensor is below this value
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub below: Option<f64>,
+    /// Distance the sensor has to move back past the threshold before the condition is no longer met
+    #[serde(default)]
+    pub hysteresis: f64,
+    pub curve: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SwitchCurve {
+    /// Conditions are checked in order. The curve of the first met condition is used
+    pub conditions: Vec<SwitchCondition>,
+    pub default: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DelayCurve {
+    pub sensor: String,
+    /// Time in seconds the input is delayed by
+    pub delay: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PeakHoldCurve {
+    pub sensor: String,
+    /// Time in seconds the maximum is held
+    pub window: f64,
+    /// Decrease per second once the maximum left the window. Drops immediately if not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub decay: Option<f64>,
+}
+
+/// Difference between a component temperature and the ambient temperature
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeltaCurve {
+    pub sensor: String,
+    pub ambient: String,
+    /// Time constant in seconds used to smooth the ambient temperature
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ambient_window: Option<f64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RampCurve {
+    pub sensor: String,
+    /// Maximum increase in percent per second
+    pub max_rise: f64,
+    /// Maximum decrease in percent per second
+    pub max_fall: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExpressionCurve {
+    pub expression: Expression,
+    /// Type of the result. Defaults to percentage
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unit: Option<common::SensorType>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -50,6 +351,13 @@ pub enum CurveFunction {
     maximum(MaximumCurve),
     average(AverageCurve),
     pid(PidCurve),
+    expression(ExpressionCurve),
+    ramp(RampCurve),
+    schedule(ScheduleCurve),
+    switch(SwitchCurve),
+    delay(DelayCurve),
+    peak_hold(PeakHoldCurve),
+    delta(DeltaCurve),
 }
 
 impl CurveFunction {
@@ -57,11 +365,151 @@ impl CurveFunction {
         match self {
             CurveFunction::linear(curve) => vec![curve.sensor.clone()],
             CurveFunction::pid(curve) => vec![curve.sensor.clone()],
+            CurveFunction::ramp(curve) => vec![curve.sensor.clone()],
+            CurveFunction::delay(curve) => vec![curve.sensor.clone()],
+            CurveFunction::peak_hold(curve) => vec![curve.sensor.clone()],
+            CurveFunction::delta(curve) => vec![curve.sensor.clone(), curve.ambient.clone()],
+            CurveFunction::switch(curve) => curve
+                .conditions
+                .iter()
+                .flat_map(|condition| [condition.sensor.clone(), condition.curve.clone()])
+                .chain(std::iter::once(curve.default.clone()))
+                .collect(),
+            CurveFunction::schedule(curve) => curve
+                .targets()
+                .filter_map(|target| match target {
+                    ScheduleTarget::Curve(id) => Some(id.clone()),
+                    ScheduleTarget::Value(_) => None,
+                })
+                .collect(),
             CurveFunction::r#static(_curve) => vec![],
-            CurveFunction::maximum(curve) => curve.sensors.clone(),
-            CurveFunction::average(curve) => curve.sensors.clone(),
+            CurveFunction::maximum(MaximumCurve { sensors })
+            | CurveFunction::average(AverageCurve { sensors }) => sensors
+                .iter()
+                .map(|sensor| sensor.id().to_string())
+                .collect(),
+            CurveFunction::expression(curve) => curve
+                .expression
+                .identifiers()
+                .into_iter()
+                .map(|(id, _column)| id.to_string())
+                .collect(),
+        }
+    }
+}
+
+impl CurveFunction {
+    /// Moves inline curves of the inputs into `curves` with ids of the form `owner[index]`
+    fn flatten_inline_curves(&mut self, owner: &str, curves: &mut Vec<FanCurve>) {
+        if let CurveFunction::maximum(MaximumCurve { sensors })
+        | CurveFunction::average(AverageCurve { sensors }) = self
+        {
+            for (index, sensor) in sensors.iter_mut().enumerate() {
+                sensor.flatten(format!("{owner}[{index}]"), curves);
+            }
+        }
+    }
+}
+
+/// Ensures the input has the expected type
+fn expect_type(
+    id: &str,
+    input: &dyn Fn(&str) -> common::SensorType,
+    expected: common::SensorType,
+) -> Result<(), String> {
+    let kind = input(id);
+    if kind != expected {
+        return Err(format!("expects {expected:?} but {id} is {kind:?}"));
+    }
+    Ok(())
+}
+
+/// Ensures all inputs have the same type and returns it
+fn same_type<'a>(
+    inputs: impl IntoIterator<Item = (&'a str, common::SensorType)>,
+) -> Result<Option<common::SensorType>, String> {
+    let mut first: Option<(&str, common::SensorType)> = None;
+    for (id, kind) in inputs {
+        match first {
+            Some((first_id, first_kind)) if first_kind != kind => {
+                return Err(format!(
+                    "mixes {first_kind:?} from {first_id} with {kind:?} from {id}"
+                ));
+            }
+            Some(_) => {}
+            None => first = Some((id, kind)),
         }
     }
+    Ok(first.map(|(_, kind)| kind))
+}
+
+impl CurveFunction {
+    /// Checks the types of the inputs and returns the type of the output.
+    /// `input` returns the type of a referenced sensor or curve
+    fn check_types(
+        &self,
+        input: &dyn Fn(&str) -> common::SensorType,
+    ) -> Result<common::SensorType, String> {
+        use common::SensorType::*;
+        let kind = match self {
+            CurveFunction::linear(curve) => {
+                // Without an explicit input the steps refer to the type of the source
+                if let Some(expected) = curve.input {
+                    expect_type(&curve.sensor, input, expected)?;
+                }
+                curve.output.unwrap_or(PERCENTAGE)
+            }
+            CurveFunction::pid(curve) => {
+                expect_type(&curve.sensor, input, TEMPERATURE)?;
+                PERCENTAGE
+            }
+            CurveFunction::r#static(_) => PERCENTAGE,
+            CurveFunction::maximum(MaximumCurve { sensors })
+            | CurveFunction::average(AverageCurve { sensors }) => same_type(
+                sensors
+                    .iter()
+                    .map(|sensor| (sensor.id(), input(sensor.id()))),
+            )?
+            .unwrap_or(PERCENTAGE),
+            CurveFunction::ramp(RampCurve { sensor, .. })
+            | CurveFunction::delay(DelayCurve { sensor, .. })
+            | CurveFunction::peak_hold(PeakHoldCurve { sensor, .. }) => input(sensor),
+            CurveFunction::delta(curve) => {
+                expect_type(&curve.sensor, input, TEMPERATURE)?;
+                expect_type(&curve.ambient, input, TEMPERATURE)?;
+                TEMPERATURE
+            }
+            CurveFunction::expression(curve) => {
+                // The unit states the type of the result, the variables have to agree
+                same_type(
+                    curve
+                        .expression
+                        .identifiers()
+                        .into_iter()
+                        .map(|(id, _column)| (id, input(id))),
+                )?;
+                curve.unit.unwrap_or(PERCENTAGE)
+            }
+            CurveFunction::switch(curve) => same_type(
+                curve
+                    .conditions
+                    .iter()
+                    .map(|condition| condition.curve.as_str())
+                    .chain(std::iter::once(curve.default.as_str()))
+                    .map(|id| (id, input(id))),
+            )?
+            .unwrap_or(PERCENTAGE),
+            // Static values are percentages
+            CurveFunction::schedule(curve) => {
+                same_type(curve.targets().map(|target| match target {
+                    ScheduleTarget::Curve(id) => (id.as_str(), input(id)),
+                    ScheduleTarget::Value(_) => ("static value", PERCENTAGE),
+                }))?
+                .unwrap_or(PERCENTAGE)
+            }
+        };
+        Ok(kind)
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -70,6 +518,15 @@ impl CurveFunction {
 pub enum SensorType {
     hwmon(HwmonConfig),
     file(FileConfig),
+    /// Group of all hwmon temperatures matching the globs. Only usable in maximum and average curves
+    glob(GlobConfig),
+}
+
+/// Globs supporting `*` and `?` for the chip name and sensor label
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GlobConfig {
+    pub chip: String,
+    pub label: String,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -83,13 +540,327 @@ pub struct SensorConfig {
     pub sensor: SensorType,
 }
 
+/// Value of a fan that can be used as curve input. Referenced by `fan_id.rpm`,
+/// `fan_id.pwm` or `fan_id.percent`. A plain `fan_id` refers to the rpm
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[allow(non_camel_case_types)]
+pub enum FanReading {
+    rpm,
+    /// Duty cycle currently written to the fan
+    pwm,
+    /// Percentage requested by the fan's curve
+    percent,
+}
+
+impl FanReading {
+    /// Splits an id into the fan id and the selected reading
+    pub fn parse(id: &str) -> (&str, FanReading) {
+        match id.rsplit_once('.') {
+            Some((fan_id, "rpm")) => (fan_id, FanReading::rpm),
+            Some((fan_id, "pwm")) => (fan_id, FanReading::pwm),
+            Some((fan_id, "percent")) => (fan_id, FanReading::percent),
+            _ => (id, FanReading::rpm),
+        }
+    }
+
+    pub fn sensor_type(&self) -> common::SensorType {
+        match self {
+            FanReading::rpm => common::SensorType::RPM,
+            FanReading::pwm | FanReading::percent => common::SensorType::PERCENTAGE,
+        }
+    }
+}
+
+/// A single curve or a list of curves
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum FanCurves {
+    Single(CurveRef),
+    Multiple(Vec<CurveRef>),
+}
+
+impl Default for FanCurves {
+    fn default() -> Self {
+        FanCurves::Multiple(vec![])
+    }
+}
+
+impl FanCurves {
+    pub fn is_empty(&self) -> bool {
+        matches!(self, FanCurves::Multiple(curves) if curves.is_empty())
+    }
+
+    pub fn ids(&self) -> Vec<&str> {
+        match self {
+            FanCurves::Single(curve) => vec![curve.id()],
+            FanCurves::Multiple(curves) => curves.iter().map(CurveRef::id).collect(),
+        }
+    }
+
+    fn refs_mut(&mut self) -> &mut [CurveRef] {
+        match self {
+            FanCurves::Single(curve) => std::slice::from_mut(curve),
+            FanCurves::Multiple(curves) => curves,
+        }
+    }
+}
+
+/// How the outputs of multiple curves of a fan are combined
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[allow(non_camel_case_types)]
+pub enum CombineRule {
+    #[default]
+    max,
+    min,
+    average,
+    /// The first curve with an output above 0%
+    priority,
+}
+
+/// What the curves of a fan control
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[allow(non_camel_case_types)]
+pub enum FanMode {
+    /// Curves output the percentage of the pwm range
+    #[default]
+    percent,
+    /// Curves output a target rpm the fan is regulated to using its tach
+    rpm,
+}
+
+/// State fans are left in when rufaco exits
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[allow(non_camel_case_types)]
+pub enum ShutdownState {
+    /// Mode and duty the fan had at startup
+    #[default]
+    restore,
+    full_speed,
+    /// Automatic control of the chip
+    auto,
+    /// Keep the last duty in manual mode
+    last,
+}
+
+fn default_rpm_p() -> f32 {
+    0.01
+}
+
+fn default_rpm_i() -> f32 {
+    0.02
+}
+
+fn default_max_rate() -> f32 {
+    10.0
+}
+
+/// Controller regulating the fan speed in rpm mode
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RpmControl {
+    /// Percent per rpm of error
+    #[serde(default = "default_rpm_p")]
+    pub p: f32,
+    #[serde(default = "default_rpm_i")]
+    pub i: f32,
+    /// Maximum change of the output in percent per second
+    #[serde(default = "default_max_rate")]
+    pub max_rate: f32,
+    /// Rpm at 100%. Used to run open-loop if the tach can't be read. Runs at 100% if unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_rpm: Option<u32>,
+}
+
+impl Default for RpmControl {
+    fn default() -> Self {
+        Self {
+            p: default_rpm_p(),
+            i: default_rpm_i(),
+            max_rate: default_max_rate(),
+            max_rpm: None,
+        }
+    }
+}
+
+fn default_stall_timeout() -> f64 {
+    10.0
+}
+
+fn default_kick_duration() -> f64 {
+    3.0
+}
+
+fn default_max_kicks() -> u32 {
+    3
+}
+
+/// Detection of fans that stop although they are driven above their start pwm
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StallConfig {
+    /// Seconds without rpm before the fan counts as stalled
+    #[serde(default = "default_stall_timeout")]
+    pub timeout: f64,
+    /// Seconds the fan is driven at full speed to recover
+    #[serde(default = "default_kick_duration")]
+    pub kick_duration: f64,
+    /// Failed kicks before the fan is put into the error state
+    #[serde(default = "default_max_kicks")]
+    pub max_kicks: u32,
+}
+
+impl Default for StallConfig {
+    fn default() -> Self {
+        Self {
+            timeout: default_stall_timeout(),
+            kick_duration: default_kick_duration(),
+            max_kicks: default_max_kicks(),
+        }
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_start_percent() -> f64 {
+    20.0
+}
+
+fn default_stop_percent() -> f64 {
+    10.0
+}
+
+fn default_stop_delay() -> f64 {
+    10.0
+}
+
+/// When a fan is stopped and started
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StartStopConfig {
+    /// Allow stopping the fan. Disable for pumps
+    #[serde(default = "default_true")]
+    pub zero_rpm: bool,
+    /// Percentage a stopped fan starts at. A spinning fan keeps spinning below it
+    #[serde(default = "default_start_percent")]
+    pub start_percent: f64,
+    /// Percentage below which the fan is stopped after `stop_delay`
+    #[serde(default = "default_stop_percent")]
+    pub stop_percent: f64,
+    /// Seconds below `stop_percent` before the fan is stopped. Prevents spin up and down loops
+    #[serde(default = "default_stop_delay")]
+    pub stop_delay: f64,
+    /// Seconds the fan runs at least once started
+    #[serde(default)]
+    pub min_on_time: f64,
+    /// Seconds the fan stays stopped at least once stopped
+    #[serde(default)]
+    pub min_off_time: f64,
+    /// Seconds at full speed when starting from standstill
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub spin_up: Option<f64>,
+}
+
+impl Default for StartStopConfig {
+    fn default() -> Self {
+        Self {
+            zero_rpm: true,
+            start_percent: default_start_percent(),
+            stop_percent: default_stop_percent(),
+            stop_delay: default_stop_delay(),
+            min_on_time: 0.0,
+            min_off_time: 0.0,
+            spin_up: None,
+        }
+    }
+}
+
+/// Measured speed of a fan over its pwm range
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FanCalibration {
+    pub max_rpm: u32,
+    /// Settled rpm for each measured pwm
+    pub rpm: BTreeMap<u8, u32>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct FanConfig {
     pub id: String,
     pub startpwm: Option<u8>,
     pub minpwm: Option<u8>,
+    /// Highest pwm written to the fan. Kicks and spin-up pulses still use full speed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub maxpwm: Option<u8>,
+    /// Lower clamp of the curves' output in percent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_percent: Option<f64>,
+    /// Upper clamp of the curves' output in percent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_percent: Option<f64>,
+    /// Pwm for each percentage. Interpolated in between and used instead of the linear pwm range.
+    /// Percentages outside of the map use the pwm of the closest entry
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pwm_map: Option<BTreeMap<u8, u8>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub calibration: Option<FanCalibration>,
+    /// Map percentages linearly onto the calibrated rpm range instead of the pwm range
+    #[serde(default)]
+    pub linearize: bool,
     pub sensor: SensorType,
-    pub curve: String,
+    /// Empty for members of a fan group
+    #[serde(default, skip_serializing_if = "FanCurves::is_empty")]
+    pub curve: FanCurves,
+    #[serde(default)]
+    pub combine: CombineRule,
+    #[serde(default)]
+    pub mode: FanMode,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rpm_control: Option<RpmControl>,
+    #[serde(default)]
+    pub shutdown: ShutdownState,
+    #[serde(default)]
+    pub stall: StallConfig,
+    #[serde(default)]
+    pub start_stop: StartStopConfig,
+}
+
+fn default_scale() -> f64 {
+    1.0
+}
+
+/// Fan of a group with the transformation of the group's output
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GroupMember {
+    pub fan: String,
+    /// Factor applied to the group's output
+    #[serde(default = "default_scale")]
+    pub scale: f64,
+    /// Added to the scaled output in the unit of the group's curve
+    #[serde(default)]
+    pub offset: f64,
+}
+
+/// How the remaining members of a group react to a failed member
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(tag = "type")]
+#[allow(non_camel_case_types)]
+pub enum FailureCompensation {
+    #[default]
+    none,
+    /// Raise the output by the percentage
+    boost {
+        percent: f64,
+    },
+    full_speed,
+}
+
+/// Fans driven together by one curve. Members start together and keep their own pwm limits
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FanGroupConfig {
+    pub id: String,
+    pub curve: CurveRef,
+    pub fans: Vec<GroupMember>,
+    /// Applied while a member stalls or its tach can't be read
+    #[serde(default)]
+    pub on_failure: FailureCompensation,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -97,6 +868,8 @@ pub struct RufacoConfig {
     pub sensors: Vec<SensorConfig>,
     pub fans: Vec<FanConfig>,
     pub curves: Vec<FanCurve>,
+    #[serde(default)]
+    pub fan_groups: Vec<FanGroupConfig>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -105,14 +878,135 @@ pub struct FanCurve {
     pub function: CurveFunction,
 }
 
-pub fn load_config(path: &str) -> RufacoConfig {
-    let config_content = std::fs::read_to_string(path).unwrap();
-    let config_yaml: RufacoConfig = serde_yaml::from_str(&config_content).unwrap();
+/// Reads the config as written, keeping inline curves. Use it to write changes back
+pub fn read_config(path: &str) -> Result<RufacoConfig, String> {
+    let config_content = std::fs::read_to_string(path)
+        .map_err(|err| format!("Couldn't read config {path}: {err}"))?;
+    serde_yaml::from_str(&config_content).map_err(|err| format!("Config {path} is invalid: {err}"))
+}
+
+/// Reads and validates the config with inline curves moved to the top level
+pub fn load_config(path: &str) -> Result<RufacoConfig, String> {
+    let mut config_yaml = read_config(path)?;
+    config_yaml.flatten_inline_curves();
     config_yaml
+        .validate()
+        .map_err(|err| format!("Config {path} is invalid: {err}"))?;
+    Ok(config_yaml)
+}
+
+pub fn save_config(config: &RufacoConfig, path: &str) {
+    let conf_string = serde_yaml::to_string(config).unwrap();
+    debug!("Writing config {:?}", conf_string);
+    std::fs::write(path, conf_string).expect("Couldn't write config file");
+}
+
+/// Whether a rate or duration from the config is a finite number and not negative
+fn is_non_negative(value: f64) -> bool {
+    value.is_finite() && value >= 0.0
+}
+
+/// Whether a rate from the config is a finite number above 0
+fn is_positive(value: f64) -> bool {
+    value.is_finite() && value > 0.0
+}
+
+/// Whether a percentage from the config lies within 0 and 100. Fails for NaN
+fn is_percentage(value: f64) -> bool {
+    (0.0..=100.0).contains(&value)
 }
 
 impl RufacoConfig {
-    fn validate(&self) -> bool {
+    /// Sets the gains of the pid curve with the given id. Returns false if no such pid curve exists
+    pub fn set_pid_gains(&mut self, curve_id: &str, p: f32, i: f32, d: f32) -> bool {
+        let curve = self.curves.iter_mut().find(|curve| curve.id == curve_id);
+        match curve {
+            Some(FanCurve {
+                function: CurveFunction::pid(pid),
+                ..
+            }) => {
+                pid.p = p;
+                pid.i = i;
+                pid.d = d;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Stores the calibration results of the fan. Returns false if no such fan exists
+    pub fn set_calibration(
+        &mut self,
+        fan_id: &str,
+        min_pwm: u8,
+        start_pwm: u8,
+        calibration: FanCalibration,
+    ) -> bool {
+        match self.fans.iter_mut().find(|fan| fan.id == fan_id) {
+            Some(fan) => {
+                fan.minpwm = Some(min_pwm);
+                fan.startpwm = Some(start_pwm);
+                fan.calibration = Some(calibration);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Moves inline curves of fans and curves to the top level with generated ids.
+    /// The id is the id of the owning fan or curve and the index of the input, e.g. `fan[0]`
+    pub fn flatten_inline_curves(&mut self) {
+        let mut inline = vec![];
+        for curve in &mut self.curves {
+            curve.function.flatten_inline_curves(&curve.id, &mut inline);
+        }
+        for fan in &mut self.fans {
+            for (index, curve) in fan.curve.refs_mut().iter_mut().enumerate() {
+                curve.flatten(format!("{}[{index}]", fan.id), &mut inline);
+            }
+        }
+        for group in &mut self.fan_groups {
+            group.curve.flatten(format!("{}[0]", group.id), &mut inline);
+        }
+        self.curves.extend(inline);
+    }
+
+    /// Group containing the fan
+    pub fn fan_group(&self, fan_id: &str) -> Option<&FanGroupConfig> {
+        self.fan_groups
+            .iter()
+            .find(|group| group.fans.iter().any(|member| member.fan == fan_id))
+    }
+
+    /// Ids of the curves driving the fan, including the curve of its group
+    pub fn fan_curve_ids<'a>(&'a self, fan: &'a FanConfig) -> Vec<&'a str> {
+        let mut ids = fan.curve.ids();
+        if let Some(group) = self.fan_group(&fan.id) {
+            ids.push(group.curve.id());
+        }
+        ids
+    }
+
+    /// Returns the fan and reading referenced by the id if it refers to a fan
+    pub fn fan_reading(&self, id: &str) -> Option<(&FanConfig, FanReading)> {
+        let (fan_id, reading) = FanReading::parse(id);
+        self.fans
+            .iter()
+            .find(|fan| fan.id == fan_id)
+            .map(|fan| (fan, reading))
+    }
+
+    /// Checks the config and returns the first problem found
+    pub fn validate(&self) -> Result<(), String> {
+        // Generated ids of inline curves can collide with other curves
+        let mut curve_ids = HashSet::new();
+        if let Some(curve) = self
+            .curves
+            .iter()
+            .find(|curve| !curve_ids.insert(curve.id.as_str()))
+        {
+            return Err(format!("Curve id {} is used more than once", curve.id));
+        }
         let graph: HashMap<String, CurveFunction> = self
             .curves
             .iter()
@@ -123,20 +1017,375 @@ impl RufacoConfig {
             .iter()
             .map(|sensor| sensor.id.clone())
             .collect();
+        let groups: HashSet<&str> = self
+            .sensors
+            .iter()
+            .filter(|sensor| matches!(sensor.sensor, SensorType::glob(_)))
+            .map(|sensor| sensor.id.as_str())
+            .collect();
+        let known = |id: &str| {
+            graph.contains_key(id) || sensors.contains(id) || self.fan_reading(id).is_some()
+        };
 
         // Ensure every curve is valid
-        for (_node_id, func) in graph.iter() {
+        for (node_id, func) in graph.iter() {
+            // Expressions point to the column of the unknown id
+            if let CurveFunction::expression(curve) = func {
+                for (id, column) in curve.expression.identifiers() {
+                    if !known(id) {
+                        return Err(format!(
+                            "Expression of curve {node_id} references unknown id {id} at column {column}: {}",
+                            curve.expression.source()
+                        ));
+                    }
+                }
+            }
             for sensor_id in func.get_sensor_ids() {
-                // sensor neither in curves nor sensors -> invalid config
-                if !graph.contains_key(&sensor_id) && !sensors.contains(&sensor_id) {
-                    return false;
+                // sensor neither in curves, sensors nor fans -> invalid config
+                if !known(&sensor_id) {
+                    return Err(format!(
+                        "Curve {node_id} references unknown sensor, curve or fan {sensor_id}"
+                    ));
+                }
+            }
+        }
+
+        for curve in &self.curves {
+            if matches!(
+                curve.function,
+                CurveFunction::maximum(_) | CurveFunction::average(_)
+            ) {
+                continue;
+            }
+            if let Some(group) = curve
+                .function
+                .get_sensor_ids()
+                .into_iter()
+                .find(|id| groups.contains(id.as_str()))
+            {
+                return Err(format!(
+                    "Curve {} uses sensor group {group}. Groups are only allowed in maximum and average curves",
+                    curve.id
+                ));
+            }
+        }
+
+        for fan in &self.fans {
+            if matches!(fan.sensor, SensorType::glob(_)) {
+                return Err(format!("Fan {} can't use a glob sensor", fan.id));
+            }
+            let groups = self
+                .fan_groups
+                .iter()
+                .filter(|group| group.fans.iter().any(|member| member.fan == fan.id))
+                .count();
+            if groups > 1 {
+                return Err(format!("Fan {} is a member of multiple fan groups", fan.id));
+            }
+            if groups == 1 && !fan.curve.is_empty() {
+                return Err(format!(
+                    "Fan {} is driven by its fan group and can't have own curves",
+                    fan.id
+                ));
+            }
+            if let Some(control) = &fan.rpm_control {
+                if !control.p.is_finite() || !control.i.is_finite() {
+                    return Err(format!("Rpm control of fan {} needs finite gains", fan.id));
+                }
+                if !is_non_negative(control.max_rate.into()) {
+                    return Err(format!(
+                        "Rpm control of fan {} needs a max_rate of at least 0",
+                        fan.id
+                    ));
+                }
+            }
+            if !is_non_negative(fan.stall.timeout) || !is_non_negative(fan.stall.kick_duration) {
+                return Err(format!(
+                    "Stall detection of fan {} needs a timeout and kick_duration of at least 0",
+                    fan.id
+                ));
+            }
+            let start_stop = &fan.start_stop;
+            let timings = [
+                Some(start_stop.stop_delay),
+                Some(start_stop.min_on_time),
+                Some(start_stop.min_off_time),
+                start_stop.spin_up,
+            ];
+            if !timings.into_iter().flatten().all(is_non_negative) {
+                return Err(format!(
+                    "Start and stop times of fan {} need to be at least 0",
+                    fan.id
+                ));
+            }
+            if !is_percentage(start_stop.start_percent) || !is_percentage(start_stop.stop_percent) {
+                return Err(format!(
+                    "Fan {} needs a start_percent and stop_percent between 0 and 100",
+                    fan.id
+                ));
+            }
+            if start_stop.stop_percent > start_stop.start_percent {
+                return Err(format!(
+                    "Fan {} has a stop_percent above its start_percent",
+                    fan.id
+                ));
+            }
+            if ![fan.min_percent, fan.max_percent]
+                .into_iter()
+                .flatten()
+                .all(is_percentage)
+            {
+                return Err(format!(
+                    "Fan {} needs a min_percent and max_percent between 0 and 100",
+                    fan.id
+                ));
+            }
+            if fan.min_percent.unwrap_or(0.0) > fan.max_percent.unwrap_or(100.0) {
+                return Err(format!(
+                    "Fan {} has a min_percent above its max_percent",
+                    fan.id
+                ));
+            }
+            if fan
+                .pwm_map
+                .as_ref()
+                .is_some_and(|map| map.is_empty() || map.keys().any(|percent| *percent > 100))
+            {
+                return Err(format!(
+                    "Pwm map of fan {} needs at least one entry and percentages of at most 100",
+                    fan.id
+                ));
+            }
+        }
+
+        for group in &self.fan_groups {
+            if let Some(member) = group
+                .fans
+                .iter()
+                .find(|member| !self.fans.iter().any(|fan| fan.id == member.fan))
+            {
+                return Err(format!(
+                    "Fan group {} references unknown fan {}",
+                    group.id, member.fan
+                ));
+            }
+            let mut members = HashSet::new();
+            if let Some(member) = group
+                .fans
+                .iter()
+                .find(|member| !members.insert(member.fan.as_str()))
+            {
+                return Err(format!(
+                    "Fan group {} lists fan {} more than once",
+                    group.id, member.fan
+                ));
+            }
+        }
+
+        for curve in &self.curves {
+            match &curve.function {
+                CurveFunction::switch(switch) => {
+                    for condition in &switch.conditions {
+                        if condition.above.is_some() == condition.below.is_some() {
+                            return Err(format!(
+                                "Condition on {} in curve {} needs either above or below",
+                                condition.sensor, curve.id
+                            ));
+                        }
+                    }
+                }
+                CurveFunction::pid(pid) => {
+                    let limits = [
+                        Some(pid.output_min),
+                        Some(pid.output_max),
+                        pid.p_limit,
+                        pid.i_limit,
+                        pid.d_limit,
+                    ];
+                    if limits.iter().flatten().any(|limit| !limit.is_finite()) {
+                        return Err(format!(
+                            "Pid curve {} has a limit that is not a number",
+                            curve.id
+                        ));
+                    }
+                    if pid.output_min > pid.output_max {
+                        return Err(format!(
+                            "Pid curve {} has an output_min above its output_max",
+                            curve.id
+                        ));
+                    }
+                    let tuning = [pid.tracking_gain, pid.derivative_filter, pid.sample_time];
+                    if !tuning
+                        .into_iter()
+                        .flatten()
+                        .all(|value| is_non_negative(value.into()))
+                    {
+                        return Err(format!(
+                            "Pid curve {} needs a tracking_gain, derivative_filter and sample_time of at least 0",
+                            curve.id
+                        ));
+                    }
+                }
+                CurveFunction::delay(delay) if !is_non_negative(delay.delay) => {
+                    return Err(format!(
+                        "Delay curve {} needs a delay of at least 0",
+                        curve.id
+                    ));
+                }
+                CurveFunction::peak_hold(peak)
+                    if !is_non_negative(peak.window) || !peak.decay.is_none_or(is_non_negative) =>
+                {
+                    return Err(format!(
+                        "Peak hold curve {} needs a window and decay of at least 0",
+                        curve.id
+                    ));
+                }
+                CurveFunction::schedule(schedule) => {
+                    if !schedule.crossfade.is_none_or(is_non_negative) {
+                        return Err(format!(
+                            "Schedule curve {} needs a crossfade of at least 0",
+                            curve.id
+                        ));
+                    }
+                    // An empty time range is never active
+                    if let Some(index) = schedule.rules.iter().position(|rule| rule.from == rule.to)
+                    {
+                        return Err(format!(
+                            "Rule {index} of schedule curve {} starts and ends at the same time",
+                            curve.id
+                        ));
+                    }
                 }
+                CurveFunction::delta(delta)
+                    if !delta.ambient_window.is_none_or(is_non_negative) =>
+                {
+                    return Err(format!(
+                        "Delta curve {} needs an ambient_window of at least 0",
+                        curve.id
+                    ));
+                }
+                // A rate of 0 would freeze the output in that direction
+                CurveFunction::ramp(ramp)
+                    if !is_positive(ramp.max_rise) || !is_positive(ramp.max_fall) =>
+                {
+                    return Err(format!(
+                        "Ramp curve {} needs a max_rise and max_fall above 0",
+                        curve.id
+                    ));
+                }
+                _ => {}
             }
         }
 
         // Check for cycles
+        if let Err(cycle) = self.sorted_curves() {
+            return Err(format!("Curves contain a cycle: {}", cycle.join(" -> ")));
+        }
+
+        // Check the types of the whole graph
+        let types = self.curve_types()?;
+        for fan in &self.fans {
+            if self.fan_curve_ids(fan).is_empty() {
+                return Err(format!("Fan {} has no curve", fan.id));
+            }
+            let expected = match fan.mode {
+                FanMode::percent => common::SensorType::PERCENTAGE,
+                FanMode::rpm => common::SensorType::RPM,
+            };
+            for curve_id in self.fan_curve_ids(fan) {
+                match types.get(curve_id) {
+                    Some(kind) if *kind == expected => {}
+                    Some(kind) => {
+                        return Err(format!(
+                            "Fan {} expects a {expected:?} curve but {curve_id} is {kind:?}",
+                            fan.id
+                        ));
+                    }
+                    None => {
+                        return Err(format!(
+                            "Fan {} references unknown curve {curve_id}",
+                            fan.id
+                        ));
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the output type of every curve. Sensors are temperatures.
+    /// Expects a config without missing references or cycles
+    pub fn curve_types(&self) -> Result<HashMap<String, common::SensorType>, String> {
+        let mut types: HashMap<String, common::SensorType> = self
+            .sensors
+            .iter()
+            .map(|sensor| (sensor.id.clone(), common::SensorType::TEMPERATURE))
+            .collect();
+        for curve in self.sorted_curves().map_err(|cycle| cycle.join(" -> "))? {
+            let kind = curve
+                .function
+                .check_types(&|id| match types.get(id) {
+                    Some(kind) => *kind,
+                    None => self.fan_reading(id).unwrap().1.sensor_type(),
+                })
+                .map_err(|err| format!("Curve {} {err}", curve.id))?;
+            types.insert(curve.id.clone(), kind);
+        }
+        Ok(types)
+    }
+
+    /// Returns the curves ordered so every curve comes after the curves it references.
+    /// Fan readings depend on the curve of the fan.
+    /// Returns the ids forming a cycle if there is one
+    pub fn sorted_curves(&self) -> Result<Vec<&FanCurve>, Vec<String>> {
+        fn visit<'a>(
+            config: &RufacoConfig,
+            curve: &'a FanCurve,
+            curves: &HashMap<&str, &'a FanCurve>,
+            path: &mut Vec<String>,
+            done: &mut HashSet<String>,
+            sorted: &mut Vec<&'a FanCurve>,
+        ) -> Result<(), Vec<String>> {
+            if done.contains(&curve.id) {
+                return Ok(());
+            }
+            if let Some(pos) = path.iter().position(|id| *id == curve.id) {
+                let mut cycle = path[pos..].to_vec();
+                cycle.push(curve.id.clone());
+                return Err(cycle);
+            }
+            path.push(curve.id.clone());
+            for sensor_id in curve.function.get_sensor_ids() {
+                if let Some(dependency) = curves.get(sensor_id.as_str()) {
+                    visit(config, dependency, curves, path, done, sorted)?;
+                } else if let Some((fan, _reading)) = config.fan_reading(&sensor_id) {
+                    path.push(fan.id.clone());
+                    for curve_id in config.fan_curve_ids(fan) {
+                        if let Some(dependency) = curves.get(curve_id) {
+                            visit(config, dependency, curves, path, done, sorted)?;
+                        }
+                    }
+                    path.pop();
+                }
+            }
+            path.pop();
+            done.insert(curve.id.clone());
+            sorted.push(curve);
+            Ok(())
+        }
 
-        true
+        let curves: HashMap<&str, &FanCurve> = self
+            .curves
+            .iter()
+            .map(|curve| (curve.id.as_str(), curve))
+            .collect();
+        let mut done = HashSet::new();
+        let mut sorted = vec![];
+        for curve in &self.curves {
+            visit(self, curve, &curves, &mut vec![], &mut done, &mut sorted)?;
+        }
+        Ok(sorted)
     }
 }
 
@@ -144,9 +1393,48 @@ impl RufacoConfig {
 mod tests {
     use std::collections::BTreeMap;
 
-    use crate::config::{FileConfig, SensorConfig, SensorType};
+    use crate::{
+        common,
+        config::{FanConfig, FileConfig, SensorConfig, SensorType},
+        expression::Expression,
+    };
+
+    use super::{load_config, read_config, CurveFunction, CurveRef, FanCurve, RufacoConfig};
+
+    /// Config from a yaml snippet. Missing sections are empty. A `temp` sensor and a
+    /// `static` curve at 50% are always available
+    fn yaml_config(yaml: &str) -> RufacoConfig {
+        let mut sections: serde_yaml::Mapping = serde_yaml::from_str(yaml).unwrap();
+        for section in ["sensors", "curves", "fans"] {
+            sections
+                .entry(section.into())
+                .or_insert(serde_yaml::Value::Sequence(vec![]));
+        }
+        let mut conf: RufacoConfig = serde_yaml::from_value(sections.into()).unwrap();
+        conf.sensors.push(SensorConfig {
+            id: "temp".to_string(),
+            sensor: SensorType::file(FileConfig {
+                path: "test".to_string(),
+            }),
+        });
+        conf.curves.push(FanCurve {
+            id: "static".to_string(),
+            function: CurveFunction::r#static(super::StaticCurve { value: 50 }),
+        });
+        conf.flatten_inline_curves();
+        conf
+    }
 
-    use super::{load_config, CurveFunction, FanCurve, RufacoConfig};
+    /// Validates each yaml snippet. `None` marks a valid config, otherwise the expected error
+    fn check_configs(cases: &[(&str, Option<&str>)]) {
+        for (yaml, error) in cases {
+            assert_eq!(
+                yaml_config(yaml).validate().err().as_deref(),
+                *error,
+                "{yaml}"
+            );
+        }
+    }
 
     #[test]
     fn minimal_config() {
@@ -165,7 +1453,7 @@ mod tests {
             sensor: test_sensor2,
         };
         let curve_func = CurveFunction::maximum(super::MaximumCurve {
-            sensors: vec!["test_sensor1".to_string(), "test_sensor2".to_string()],
+            sensors: vec!["test_sensor1".into(), "test_sensor2".into()],
         });
         let curve = FanCurve {
             id: "test_curve".to_string(),
@@ -176,20 +1464,50 @@ mod tests {
             sensors: vec![sensor_config, sensor_config2],
             fans: vec![],
             curves,
+            fan_groups: vec![],
         };
 
-        assert!(conf.validate());
+        conf.validate().unwrap();
         let conf_empty = RufacoConfig {
             sensors: vec![],
             fans: vec![],
             curves: vec![],
+            fan_groups: vec![],
         };
-        assert!(conf_empty.validate());
+        conf_empty.validate().unwrap();
     }
     #[test]
     fn all_curves_file() {
-        let config = load_config("test/all_curves.yaml");
-        assert!(config.validate());
+        let config = load_config("test/all_curves.yaml").unwrap();
+        config.validate().unwrap();
+        assert!(load_config("test/missing.yaml")
+            .unwrap_err()
+            .starts_with("Couldn't read config test/missing.yaml"));
+
+        // Inline curves stay inline when the config is written back
+        let written = read_config("test/all_curves.yaml").unwrap();
+        assert!(written.curves.len() < config.curves.len());
+        let Some(CurveFunction::maximum(max)) = written
+            .curves
+            .iter()
+            .find(|curve| curve.id == "inline_max_curve")
+            .map(|curve| &curve.function)
+        else {
+            panic!("inline_max_curve is no maximum curve");
+        };
+        assert!(matches!(max.sensors[1], CurveRef::Inline(_)));
+    }
+
+    #[test]
+    fn save_unset_options() {
+        // Unset options are left out when the config is written back
+        let conf = yaml_config(
+            "
+curves: [{id: pid, function: {type: pid, sensor: temp, target: 50, p: 1, i: 0.1, d: 0}}]
+fans: [{id: fan, minpwm: 20, startpwm: 40, sensor: {type: hwmon, chip: nct6775, name: fan1}, curve: pid}]",
+        );
+        let yaml = serde_yaml::to_string(&conf).unwrap();
+        assert!(!yaml.contains("null"), "{yaml}");
     }
 
     #[test]
@@ -211,7 +1529,7 @@ mod tests {
 
         let curve_funcs = vec![
             CurveFunction::maximum(super::MaximumCurve {
-                sensors: vec!["test_sensor1".to_string(), "test_sensor2".to_string()],
+                sensors: vec!["test_sensor1".into(), "test_sensor2".into()],
             }),
             CurveFunction::pid(super::PidCurve {
                 p: 1.0,
@@ -219,14 +1537,31 @@ mod tests {
                 d: 1.0,
                 sensor: "test_sensor1".to_string(),
                 target: 5.0,
+                ..Default::default()
             }),
             CurveFunction::linear(super::LinearCurve {
                 sensor: "test_sensor1".to_string(),
                 steps: BTreeMap::new(),
+                input: None,
+                output: None,
             }),
             CurveFunction::r#static(super::StaticCurve { value: 1 }),
             CurveFunction::average(super::AverageCurve {
-                sensors: vec!["test_sensor1".to_string(), "test_sensor2".to_string()],
+                sensors: vec!["test_sensor1".into(), "test_sensor2".into()],
+            }),
+            CurveFunction::ramp(super::RampCurve {
+                sensor: "test_sensor1".to_string(),
+                max_rise: 10.0,
+                max_fall: 2.0,
+            }),
+            CurveFunction::delay(super::DelayCurve {
+                sensor: "test_sensor1".to_string(),
+                delay: 30.0,
+            }),
+            CurveFunction::peak_hold(super::PeakHoldCurve {
+                sensor: "test_sensor1".to_string(),
+                window: 60.0,
+                decay: Some(1.0),
             }),
         ];
 
@@ -246,9 +1581,10 @@ mod tests {
             sensors: vec![sensor_config, sensor_config2],
             fans: vec![],
             curves,
+            fan_groups: vec![],
         };
 
-        assert!(conf.validate());
+        conf.validate().unwrap();
     }
 
     #[test]
@@ -261,7 +1597,7 @@ mod tests {
             sensor: test_sensor,
         };
         let curve_func = CurveFunction::maximum(super::MaximumCurve {
-            sensors: vec!["invalid".to_string()],
+            sensors: vec!["invalid".into()],
         });
         let curve = FanCurve {
             id: "test_curve".to_string(),
@@ -274,8 +1610,884 @@ mod tests {
             sensors: vec![sensor_config],
             fans: vec![],
             curves,
+            fan_groups: vec![],
+        };
+
+        assert!(conf.validate().is_err());
+    }
+
+    #[test]
+    fn expression_config() {
+        let sensor_config = SensorConfig {
+            id: "test_sensor1".to_string(),
+            sensor: SensorType::file(FileConfig {
+                path: "test".to_string(),
+            }),
+        };
+        let curve = FanCurve {
+            id: "test_curve".to_string(),
+            function: CurveFunction::expression(super::ExpressionCurve {
+                expression: Expression::parse("max(test_sensor1, invalid)").unwrap(),
+                unit: None,
+            }),
+        };
+        let mut conf = RufacoConfig {
+            sensors: vec![sensor_config],
+            fans: vec![],
+            curves: vec![curve],
+            fan_groups: vec![],
+        };
+        assert!(conf.validate().is_err());
+
+        conf.curves[0].function = CurveFunction::expression(super::ExpressionCurve {
+            expression: Expression::parse("max(test_sensor1, 20)").unwrap(),
+            unit: None,
+        });
+        conf.validate().unwrap();
+
+        // Temperatures can't be mixed with rpm
+        conf.curves[0].function = CurveFunction::expression(super::ExpressionCurve {
+            expression: Expression::parse("max(test_sensor1, fan.rpm)").unwrap(),
+            unit: None,
+        });
+        conf.curves.push(FanCurve {
+            id: "static".to_string(),
+            function: CurveFunction::r#static(super::StaticCurve { value: 50 }),
+        });
+        conf.fans.push(
+            serde_yaml::from_str("id: fan\nsensor:\n  type: file\n  path: test\ncurve: static")
+                .unwrap(),
+        );
+        assert!(conf.validate().is_err());
+        assert_eq!(
+            conf.curve_types().unwrap_err(),
+            "Curve test_curve mixes TEMPERATURE from test_sensor1 with RPM from fan.rpm"
+        );
+
+        let err = serde_yaml::from_str::<FanCurve>(
+            "id: test\nfunction:\n  type: expression\n  expression: \"max(a,, b)\"",
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("invalid expression at column 7"));
+    }
+
+    #[test]
+    fn set_pid_gains() {
+        let mut config = load_config("test/all_curves.yaml").unwrap();
+        assert!(config.set_pid_gains("pid_curve", 2.0, 0.1, 0.5));
+        let Some(CurveFunction::pid(pid)) = config
+            .curves
+            .iter()
+            .find(|curve| curve.id == "pid_curve")
+            .map(|curve| &curve.function)
+        else {
+            panic!("pid_curve is missing");
         };
+        assert_eq!((pid.p, pid.i, pid.d), (2.0, 0.1, 0.5));
+        assert!(!config.set_pid_gains("linear_curve", 2.0, 0.1, 0.5));
+        assert!(!config.set_pid_gains("invalid", 2.0, 0.1, 0.5));
+    }
+
+    #[test]
+    fn pid_limits_config() {
+        let limit_error = "Pid curve pid has a limit that is not a number";
+        let tuning_error =
+            "Pid curve pid needs a tracking_gain, derivative_filter and sample_time of at least 0";
+        check_configs(&[
+            (
+                "curves: [{id: pid, function: {type: pid, sensor: temp, target: 50, p: 1, i: 0.1, d: 0, output_min: 20, output_max: 80, p_limit: 30}}]",
+                None,
+            ),
+            (
+                "curves: [{id: pid, function: {type: pid, sensor: temp, target: 50, p: 1, i: 0.1, d: 0, sample_time: 2, derivative_filter: 5, tracking_gain: 0.5}}]",
+                None,
+            ),
+            (
+                "curves: [{id: pid, function: {type: pid, sensor: temp, target: 50, p: 1, i: 0.1, d: 0, output_min: 80, output_max: 20}}]",
+                Some("Pid curve pid has an output_min above its output_max"),
+            ),
+            (
+                "curves: [{id: pid, function: {type: pid, sensor: temp, target: 50, p: 1, i: 0.1, d: 0, output_max: .inf}}]",
+                Some(limit_error),
+            ),
+            (
+                "curves: [{id: pid, function: {type: pid, sensor: temp, target: 50, p: 1, i: 0.1, d: 0, p_limit: .nan}}]",
+                Some(limit_error),
+            ),
+            (
+                "curves: [{id: pid, function: {type: pid, sensor: temp, target: 50, p: 1, i: 0.1, d: 0, sample_time: -1}}]",
+                Some(tuning_error),
+            ),
+            (
+                "curves: [{id: pid, function: {type: pid, sensor: temp, target: 50, p: 1, i: 0.1, d: 0, derivative_filter: .nan}}]",
+                Some(tuning_error),
+            ),
+            (
+                "curves: [{id: pid, function: {type: pid, sensor: temp, target: 50, p: 1, i: 0.1, d: 0, tracking_gain: -0.5}}]",
+                Some(tuning_error),
+            ),
+        ]);
+    }
+
+    #[test]
+    fn delay_config() {
+        check_configs(&[
+            (
+                "curves: [{id: delay, function: {type: delay, sensor: static, delay: 10}}]",
+                None,
+            ),
+            (
+                "curves: [{id: delay, function: {type: delay, sensor: static, delay: -1}}]",
+                Some("Delay curve delay needs a delay of at least 0"),
+            ),
+            (
+                "curves: [{id: delay, function: {type: delay, sensor: static, delay: .nan}}]",
+                Some("Delay curve delay needs a delay of at least 0"),
+            ),
+            (
+                "curves: [{id: peak, function: {type: peak_hold, sensor: static, window: 30, decay: 1}}]",
+                None,
+            ),
+            (
+                "curves: [{id: peak, function: {type: peak_hold, sensor: static, window: .nan}}]",
+                Some("Peak hold curve peak needs a window and decay of at least 0"),
+            ),
+            (
+                "curves: [{id: peak, function: {type: peak_hold, sensor: static, window: 30, decay: -1}}]",
+                Some("Peak hold curve peak needs a window and decay of at least 0"),
+            ),
+        ]);
+    }
+
+    #[test]
+    fn ramp_config() {
+        let error = "Ramp curve ramp needs a max_rise and max_fall above 0";
+        check_configs(&[
+            (
+                "curves: [{id: ramp, function: {type: ramp, sensor: static, max_rise: 5, max_fall: 2}}]",
+                None,
+            ),
+            (
+                "curves: [{id: ramp, function: {type: ramp, sensor: static, max_rise: 5, max_fall: 0}}]",
+                Some(error),
+            ),
+            (
+                "curves: [{id: ramp, function: {type: ramp, sensor: static, max_rise: -5, max_fall: 2}}]",
+                Some(error),
+            ),
+            (
+                "curves: [{id: ramp, function: {type: ramp, sensor: static, max_rise: 5, max_fall: .nan}}]",
+                Some(error),
+            ),
+            (
+                "curves: [{id: ramp, function: {type: ramp, sensor: static, max_rise: .inf, max_fall: 2}}]",
+                Some(error),
+            ),
+        ]);
+    }
+
+    #[test]
+    fn schedule_config() {
+        let curve: super::ScheduleCurve = serde_yaml::from_str(
+            "default: 40\nrules:\n  - from: \"22:00\"\n    to: \"07:30\"\n    days: [fri]\n    target: quiet",
+        )
+        .unwrap();
+        assert_eq!(curve.default, super::ScheduleTarget::Value(40));
+        let rule = &curve.rules[0];
+        assert_eq!(rule.to, super::TimeOfDay(7 * 60 + 30));
+        assert_eq!(
+            rule.target,
+            super::ScheduleTarget::Curve("quiet".to_string())
+        );
+        assert!(rule.is_active(super::Weekday::fri, super::TimeOfDay(23 * 60)));
+        assert!(rule.is_active(super::Weekday::sat, super::TimeOfDay(7 * 60)));
+        assert!(!rule.is_active(super::Weekday::sat, super::TimeOfDay(23 * 60)));
+        assert!(!rule.is_active(super::Weekday::fri, super::TimeOfDay(7 * 60)));
+
+        let err = serde_yaml::from_str::<super::ScheduleRule>(
+            "from: \"25:00\"\nto: \"07:00\"\ntarget: quiet",
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("invalid time of day `25:00`"));
+
+        check_configs(&[
+            (
+                "curves: [{id: schedule, function: {type: schedule, default: 40, crossfade: 30, rules: [{from: '22:00', to: '07:30', target: static}]}}]",
+                None,
+            ),
+            (
+                "curves: [{id: schedule, function: {type: schedule, default: 40, crossfade: -30, rules: []}}]",
+                Some("Schedule curve schedule needs a crossfade of at least 0"),
+            ),
+            (
+                "curves: [{id: schedule, function: {type: schedule, default: 40, rules: [{from: '22:00', to: '22:00', target: static}]}}]",
+                Some("Rule 0 of schedule curve schedule starts and ends at the same time"),
+            ),
+        ]);
+    }
+
+    #[test]
+    fn switch_config() {
+        let sensor_config = SensorConfig {
+            id: "test_sensor1".to_string(),
+            sensor: SensorType::file(FileConfig {
+                path: "test".to_string(),
+            }),
+        };
+        let switch = |default: &str, below: Option<f64>| {
+            CurveFunction::switch(super::SwitchCurve {
+                conditions: vec![super::SwitchCondition {
+                    sensor: "test_sensor1".to_string(),
+                    above: Some(70.0),
+                    below,
+                    hysteresis: 2.0,
+                    curve: "static".to_string(),
+                }],
+                default: default.to_string(),
+            })
+        };
+        let mut conf = RufacoConfig {
+            sensors: vec![sensor_config],
+            fans: vec![],
+            curves: vec![
+                FanCurve {
+                    id: "switch".to_string(),
+                    function: switch("static", None),
+                },
+                FanCurve {
+                    id: "static".to_string(),
+                    function: CurveFunction::r#static(super::StaticCurve { value: 1 }),
+                },
+            ],
+            fan_groups: vec![],
+        };
+        conf.validate().unwrap();
+        // Dependencies come first
+        let order: Vec<&str> = conf
+            .sorted_curves()
+            .unwrap()
+            .iter()
+            .map(|curve| curve.id.as_str())
+            .collect();
+        assert_eq!(order, vec!["static", "switch"]);
+
+        conf.curves[0].function = switch("invalid", None);
+        assert!(conf.validate().is_err());
+        conf.curves[0].function = switch("static", Some(20.0));
+        assert!(conf.validate().is_err());
+    }
+
+    #[test]
+    fn cycle_config() {
+        let max = |sensors: &[&str]| {
+            CurveFunction::maximum(super::MaximumCurve {
+                sensors: sensors.iter().map(|id| (*id).into()).collect(),
+            })
+        };
+        let conf = RufacoConfig {
+            sensors: vec![],
+            fans: vec![],
+            curves: vec![
+                FanCurve {
+                    id: "a".to_string(),
+                    function: max(&["b"]),
+                },
+                FanCurve {
+                    id: "b".to_string(),
+                    function: max(&["c"]),
+                },
+                FanCurve {
+                    id: "c".to_string(),
+                    function: max(&["a"]),
+                },
+            ],
+            fan_groups: vec![],
+        };
+        assert!(conf.validate().is_err());
+        assert_eq!(conf.sorted_curves().unwrap_err(), vec!["a", "b", "c", "a"]);
+    }
+
+    #[test]
+    fn delta_config() {
+        check_configs(&[
+            (
+                "
+sensors: [{id: room, sensor: {type: file, path: test}}]
+curves: [{id: delta, function: {type: delta, sensor: temp, ambient: room, ambient_window: 600}}]",
+                None,
+            ),
+            // Percentage curves are not temperatures
+            (
+                "curves: [{id: delta, function: {type: delta, sensor: temp, ambient: static}}]",
+                Some("Curve delta expects TEMPERATURE but static is PERCENTAGE"),
+            ),
+            (
+                "
+sensors: [{id: room, sensor: {type: file, path: test}}]
+curves: [{id: delta, function: {type: delta, sensor: temp, ambient: room, ambient_window: -1}}]",
+                Some("Delta curve delta needs an ambient_window of at least 0"),
+            ),
+        ]);
+    }
+
+    #[test]
+    fn type_config() {
+        let sensor_config = SensorConfig {
+            id: "temp".to_string(),
+            sensor: SensorType::file(FileConfig {
+                path: "test".to_string(),
+            }),
+        };
+        let curve = |id: &str, function: CurveFunction| FanCurve {
+            id: id.to_string(),
+            function,
+        };
+        let linear = |sensor: &str, input| {
+            CurveFunction::linear(super::LinearCurve {
+                sensor: sensor.to_string(),
+                steps: BTreeMap::new(),
+                input,
+                output: None,
+            })
+        };
+        let max = |sensors: &[&str]| {
+            CurveFunction::maximum(super::MaximumCurve {
+                sensors: sensors.iter().map(|id| (*id).into()).collect(),
+            })
+        };
+        let mut conf = RufacoConfig {
+            sensors: vec![sensor_config],
+            fans: vec![FanConfig {
+                id: "fan".to_string(),
+                minpwm: None,
+                startpwm: None,
+                maxpwm: None,
+                min_percent: None,
+                max_percent: None,
+                pwm_map: None,
+                calibration: None,
+                linearize: false,
+                sensor: SensorType::file(FileConfig {
+                    path: "test".to_string(),
+                }),
+                curve: super::FanCurves::Single("max".into()),
+                combine: Default::default(),
+                mode: Default::default(),
+                rpm_control: None,
+                shutdown: Default::default(),
+                stall: Default::default(),
+                start_stop: Default::default(),
+            }],
+            curves: vec![
+                curve("linear", linear("temp", None)),
+                curve(
+                    "static",
+                    CurveFunction::r#static(super::StaticCurve { value: 1 }),
+                ),
+                curve("max", max(&["linear", "static"])),
+            ],
+            fan_groups: vec![],
+        };
+        conf.validate().unwrap();
+        let types = conf.curve_types().unwrap();
+        assert_eq!(types["max"], common::SensorType::PERCENTAGE);
+
+        // Without an input the linear curve takes the type of its source
+        conf.curves[0] = curve("linear", linear("static", None));
+        conf.validate().unwrap();
+        conf.curves[0] = curve(
+            "linear",
+            linear("static", Some(common::SensorType::PERCENTAGE)),
+        );
+        conf.validate().unwrap();
+        // An explicit input has to match the source
+        conf.curves[0] = curve(
+            "linear",
+            linear("static", Some(common::SensorType::TEMPERATURE)),
+        );
+        assert_eq!(
+            conf.validate().unwrap_err(),
+            "Curve linear expects TEMPERATURE but static is PERCENTAGE"
+        );
+        conf.curves[0] = curve("linear", linear("temp", None));
+
+        // Maximum of a temperature and a percentage
+        conf.curves[2] = curve("max", max(&["temp", "static"]));
+        assert!(conf.validate().is_err());
+
+        // Fans need percentages
+        conf.curves[2] = curve("max", max(&["temp"]));
+        assert_eq!(
+            conf.curve_types().unwrap()["max"],
+            common::SensorType::TEMPERATURE
+        );
+        assert!(conf.validate().is_err());
+    }
+
+    #[test]
+    fn fan_reading_config() {
+        let fan = |id: &str, curve: &str| FanConfig {
+            id: id.to_string(),
+            minpwm: None,
+            startpwm: None,
+            maxpwm: None,
+            min_percent: None,
+            max_percent: None,
+            pwm_map: None,
+            calibration: None,
+            linearize: false,
+            sensor: SensorType::file(FileConfig {
+                path: "test".to_string(),
+            }),
+            curve: super::FanCurves::Single(curve.into()),
+            combine: Default::default(),
+            mode: Default::default(),
+            rpm_control: None,
+            shutdown: Default::default(),
+            stall: Default::default(),
+            start_stop: Default::default(),
+        };
+        let follow = |sensor: &str| FanCurve {
+            id: "follow".to_string(),
+            function: CurveFunction::expression(super::ExpressionCurve {
+                expression: Expression::parse(&format!("{sensor} + 10")).unwrap(),
+                unit: None,
+            }),
+        };
+        let mut conf = RufacoConfig {
+            sensors: vec![],
+            fans: vec![fan("cpu_fan", "static"), fan("case_fan", "follow")],
+            curves: vec![
+                FanCurve {
+                    id: "static".to_string(),
+                    function: CurveFunction::r#static(super::StaticCurve { value: 40 }),
+                },
+                follow("cpu_fan.percent"),
+            ],
+            fan_groups: vec![],
+        };
+        conf.validate().unwrap();
+        assert_eq!(
+            conf.fan_reading("cpu_fan.pwm")
+                .map(|(fan, reading)| (&fan.id, reading)),
+            Some((&"cpu_fan".to_string(), super::FanReading::pwm))
+        );
+        assert_eq!(
+            conf.fan_reading("cpu_fan").unwrap().1,
+            super::FanReading::rpm
+        );
+        assert!(conf.fan_reading("gpu_fan.rpm").is_none());
+
+        // Unknown fan
+        conf.curves[1] = follow("gpu_fan.percent");
+        assert!(conf.validate().is_err());
+
+        // Fans are rpm and can't drive a linear curve expecting temperatures
+        conf.curves[1] = FanCurve {
+            id: "follow".to_string(),
+            function: CurveFunction::linear(super::LinearCurve {
+                sensor: "cpu_fan".to_string(),
+                steps: BTreeMap::new(),
+                input: Some(common::SensorType::TEMPERATURE),
+                output: None,
+            }),
+        };
+        assert!(conf.validate().is_err());
+
+        // The fan driven by the curve can't be its input
+        conf.curves[1] = follow("case_fan.rpm");
+        assert!(conf.validate().is_err());
+        assert_eq!(
+            conf.sorted_curves().unwrap_err(),
+            vec!["follow", "case_fan", "follow"]
+        );
+    }
+
+    #[test]
+    fn fan_curves_config() {
+        let mut conf: RufacoConfig = serde_yaml::from_str(
+            "
+sensors: []
+curves:
+  - id: cpu
+    function:
+      type: static
+      value: 20
+  - id: gpu
+    function:
+      type: static
+      value: 40
+fans:
+  - id: single
+    sensor:
+      type: file
+      path: test
+    curve: cpu
+  - id: multiple
+    sensor:
+      type: file
+      path: test
+    curve: [cpu, gpu]
+    combine: priority
+",
+        )
+        .unwrap();
+        conf.validate().unwrap();
+        assert_eq!(conf.fans[0].curve.ids(), ["cpu"]);
+        assert_eq!(conf.fans[0].combine, super::CombineRule::max);
+        assert_eq!(conf.fans[1].curve.ids(), ["cpu", "gpu"]);
+        assert_eq!(conf.fans[1].combine, super::CombineRule::priority);
+
+        conf.fans[1].curve = super::FanCurves::Multiple(vec!["cpu".into(), "drive".into()]);
+        assert!(conf.validate().is_err());
+        conf.fans[1].curve = super::FanCurves::Multiple(vec![]);
+        assert!(conf.validate().is_err());
+    }
+
+    #[test]
+    fn inline_config() {
+        let mut conf: RufacoConfig = serde_yaml::from_str(
+            "
+sensors:
+  - id: cpu
+    sensor:
+      type: file
+      path: test
+curves:
+  - id: max
+    function:
+      type: maximum
+      sensors:
+        - cpu_curve
+        - type: average
+          sensors:
+            - type: static
+              value: 20
+            - cpu_curve
+  - id: cpu_curve
+    function:
+      type: linear
+      sensor: cpu
+      steps:
+        40: 20
+        80: 100
+fans:
+  - id: fan
+    sensor:
+      type: file
+      path: test
+    curve:
+      - max
+      - type: linear
+        sensor: cpu
+        steps:
+          60: 50
+",
+        )
+        .unwrap();
+        conf.flatten_inline_curves();
+        conf.validate().unwrap();
+        let ids: Vec<&str> = conf.curves.iter().map(|curve| curve.id.as_str()).collect();
+        assert_eq!(ids, ["max", "cpu_curve", "max[1][0]", "max[1]", "fan[1]"]);
+        assert_eq!(conf.fans[0].curve.ids(), ["max", "fan[1]"]);
+
+        // Generated ids show up in diagnostics
+        let mut conf: RufacoConfig = serde_yaml::from_str(
+            "
+sensors:
+  - id: cpu
+    sensor:
+      type: file
+      path: test
+curves: []
+fans:
+  - id: fan
+    sensor:
+      type: file
+      path: test
+    curve:
+      type: maximum
+      sensors:
+        - cpu
+        - type: static
+          value: 20
+",
+        )
+        .unwrap();
+        conf.flatten_inline_curves();
+        assert!(conf.validate().is_err());
+        assert_eq!(
+            conf.curve_types().unwrap_err(),
+            "Curve fan[0] mixes TEMPERATURE from cpu with PERCENTAGE from fan[0][1]"
+        );
+
+        // The generated id of the inline curve is already taken
+        let mut conf: RufacoConfig = serde_yaml::from_str(
+            "
+sensors: []
+curves:
+  - id: fan[0]
+    function:
+      type: static
+      value: 20
+fans:
+  - id: fan
+    sensor:
+      type: file
+      path: test
+    curve:
+      type: static
+      value: 30
+",
+        )
+        .unwrap();
+        conf.flatten_inline_curves();
+        assert!(conf.validate().is_err());
+    }
+
+    #[test]
+    fn fan_group_config() {
+        let mut conf: RufacoConfig = serde_yaml::from_str(
+            "
+sensors: []
+curves: []
+fans:
+  - id: front
+    sensor:
+      type: file
+      path: test
+  - id: back
+    sensor:
+      type: file
+      path: test
+fan_groups:
+  - id: radiator
+    curve:
+      type: static
+      value: 40
+    fans:
+      - fan: front
+      - fan: back
+        scale: 0.8
+        offset: 5
+    on_failure:
+      type: boost
+      percent: 20
+",
+        )
+        .unwrap();
+        conf.flatten_inline_curves();
+        conf.validate().unwrap();
+        let back = &conf.fans[1];
+        assert_eq!(conf.fan_curve_ids(back), ["radiator[0]"]);
+        assert_eq!(conf.fan_group("back").unwrap().fans[1].scale, 0.8);
+        assert_eq!(conf.fan_groups[0].fans[0].scale, 1.0);
+        assert_eq!(conf.fan_groups[0].fans[0].offset, 0.0);
+        assert_eq!(
+            conf.fan_groups[0].on_failure,
+            super::FailureCompensation::boost { percent: 20.0 }
+        );
+
+        // Members can't have own curves or be in several groups
+        conf.fans[0].curve = super::FanCurves::Single("radiator[0]".into());
+        assert!(conf.validate().is_err());
+        conf.fans[0].curve = Default::default();
+        conf.fan_groups.push(super::FanGroupConfig {
+            id: "second".to_string(),
+            curve: "radiator[0]".into(),
+            fans: vec![super::GroupMember {
+                fan: "front".to_string(),
+                scale: 1.0,
+                offset: 0.0,
+            }],
+            on_failure: Default::default(),
+        });
+        assert!(conf.validate().is_err());
+        conf.fan_groups[1].fans[0].fan = "missing".to_string();
+        assert!(conf.validate().is_err());
+        conf.fan_groups.pop();
+        conf.validate().unwrap();
+        let front = conf.fan_groups[0].fans[0].clone();
+        conf.fan_groups[0].fans.push(front);
+        assert!(conf.validate().is_err());
+        conf.fan_groups[0].fans.pop();
+
+        // Fans outside of groups still need a curve
+        conf.fan_groups[0].fans.pop();
+        assert!(conf.validate().is_err());
+    }
+
+    #[test]
+    fn pwm_map_config() {
+        let error = "Pwm map of fan fan needs at least one entry and percentages of at most 100";
+        check_configs(&[
+            ("fans: [{id: fan, sensor: {type: file, path: test}, curve: static, pwm_map: {20: 60, 100: 255}}]", None),
+            ("fans: [{id: fan, sensor: {type: file, path: test}, curve: static, pwm_map: {20: 60, 120: 255}}]", Some(error)),
+            ("fans: [{id: fan, sensor: {type: file, path: test}, curve: static, pwm_map: {}}]", Some(error)),
+        ]);
+    }
+
+    #[test]
+    fn percent_limits_config() {
+        let error = "Fan fan needs a min_percent and max_percent between 0 and 100";
+        check_configs(&[
+            ("fans: [{id: fan, sensor: {type: file, path: test}, curve: static, min_percent: 20, max_percent: 80}]", None),
+            ("fans: [{id: fan, sensor: {type: file, path: test}, curve: static, min_percent: 20}]", None),
+            ("fans: [{id: fan, sensor: {type: file, path: test}, curve: static, min_percent: 80, max_percent: 20}]", Some("Fan fan has a min_percent above its max_percent")),
+            ("fans: [{id: fan, sensor: {type: file, path: test}, curve: static, min_percent: .nan}]", Some(error)),
+            ("fans: [{id: fan, sensor: {type: file, path: test}, curve: static, max_percent: 120}]", Some(error)),
+            ("fans: [{id: fan, sensor: {type: file, path: test}, curve: static, min_percent: -10}]", Some(error)),
+        ]);
+    }
+
+    #[test]
+    fn stall_config() {
+        let error = "Stall detection of fan fan needs a timeout and kick_duration of at least 0";
+        check_configs(&[
+            (
+                "fans: [{id: fan, sensor: {type: file, path: test}, curve: static, stall: {timeout: 5, kick_duration: 2, max_kicks: 5}}]",
+                None,
+            ),
+            (
+                "fans: [{id: fan, sensor: {type: file, path: test}, curve: static, stall: {timeout: -5}}]",
+                Some(error),
+            ),
+            (
+                "fans: [{id: fan, sensor: {type: file, path: test}, curve: static, stall: {kick_duration: .nan}}]",
+                Some(error),
+            ),
+            (
+                "fans: [{id: fan, sensor: {type: file, path: test}, curve: static, stall: {timeout: .inf}}]",
+                Some(error),
+            ),
+        ]);
+    }
+
+    #[test]
+    fn start_stop_config() {
+        let range_error = "Fan fan needs a start_percent and stop_percent between 0 and 100";
+        let time_error = "Start and stop times of fan fan need to be at least 0";
+        check_configs(&[
+            ("fans: [{id: fan, sensor: {type: file, path: test}, curve: static, start_stop: {start_percent: 30, stop_percent: 15, spin_up: 2}}]", None),
+            ("fans: [{id: fan, sensor: {type: file, path: test}, curve: static, start_stop: {start_percent: 30, stop_percent: 40}}]", Some("Fan fan has a stop_percent above its start_percent")),
+            ("fans: [{id: fan, sensor: {type: file, path: test}, curve: static, start_stop: {start_percent: .nan, stop_percent: 15}}]", Some(range_error)),
+            ("fans: [{id: fan, sensor: {type: file, path: test}, curve: static, start_stop: {start_percent: 120, stop_percent: 110}}]", Some(range_error)),
+            ("fans: [{id: fan, sensor: {type: file, path: test}, curve: static, start_stop: {start_percent: 30, stop_percent: -10}}]", Some(range_error)),
+            ("fans: [{id: fan, sensor: {type: file, path: test}, curve: static, start_stop: {spin_up: -2}}]", Some(time_error)),
+            ("fans: [{id: fan, sensor: {type: file, path: test}, curve: static, start_stop: {min_off_time: .inf}}]", Some(time_error)),
+        ]);
+    }
+
+    #[test]
+    fn glob_config() {
+        let mut conf: RufacoConfig = serde_yaml::from_str(
+            "
+sensors:
+  - id: cores
+    sensor:
+      type: glob
+      chip: coretemp
+      label: Core *
+curves:
+  - id: max
+    function:
+      type: maximum
+      sensors:
+        - cores
+  - id: cpu
+    function:
+      type: linear
+      sensor: max
+      steps:
+        40: 20
+fans: []
+",
+        )
+        .unwrap();
+        conf.validate().unwrap();
+        assert_eq!(
+            conf.curve_types().unwrap()["max"],
+            common::SensorType::TEMPERATURE
+        );
+
+        // Groups can't be used as a single sensor
+        conf.curves[1] = FanCurve {
+            id: "cpu".to_string(),
+            function: CurveFunction::linear(super::LinearCurve {
+                sensor: "cores".to_string(),
+                steps: BTreeMap::new(),
+                input: None,
+                output: None,
+            }),
+        };
+        assert!(conf.validate().is_err());
+    }
+
+    #[test]
+    fn rpm_mode_config() {
+        let conf: RufacoConfig = serde_yaml::from_str(
+            "
+sensors:
+  - id: cpu
+    sensor:
+      type: file
+      path: test
+curves:
+  - id: cpu_rpm
+    function:
+      type: linear
+      sensor: cpu
+      output: rpm
+      steps:
+        40: 600
+        80: 1800
+fans:
+  - id: fan
+    sensor:
+      type: file
+      path: test
+    curve: cpu_rpm
+    mode: rpm
+    rpm_control:
+      max_rpm: 2000
+",
+        )
+        .unwrap();
+        conf.validate().unwrap();
+        assert_eq!(
+            conf.fans[0].rpm_control,
+            Some(super::RpmControl {
+                max_rpm: Some(2000),
+                ..Default::default()
+            })
+        );
 
-        assert!(!conf.validate());
+        check_configs(&[
+            (
+                "
+curves: [{id: rpm, function: {type: linear, sensor: temp, output: rpm, steps: {40: 600}}}]
+fans: [{id: fan, sensor: {type: file, path: test}, curve: rpm, mode: rpm, rpm_control: {p: 0.1, i: 0.05, max_rate: 20}}]",
+                None,
+            ),
+            (
+                "
+curves: [{id: rpm, function: {type: linear, sensor: temp, output: rpm, steps: {40: 600}}}]
+fans: [{id: fan, sensor: {type: file, path: test}, curve: rpm, mode: rpm, rpm_control: {max_rate: -1}}]",
+                Some("Rpm control of fan fan needs a max_rate of at least 0"),
+            ),
+            (
+                "
+curves: [{id: rpm, function: {type: linear, sensor: temp, output: rpm, steps: {40: 600}}}]
+fans: [{id: fan, sensor: {type: file, path: test}, curve: rpm, mode: rpm, rpm_control: {i: .nan}}]",
+                Some("Rpm control of fan fan needs finite gains"),
+            ),
+            // Rpm curves can't drive fans in percent mode
+            (
+                "
+curves: [{id: rpm, function: {type: linear, sensor: temp, output: rpm, steps: {40: 600}}}]
+fans: [{id: fan, sensor: {type: file, path: test}, curve: rpm, mode: percent}]",
+                Some("Fan fan expects a PERCENTAGE curve but rpm is RPM"),
+            ),
+        ]);
     }
 }
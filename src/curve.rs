@@ -1,17 +1,18 @@
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, HashMap, VecDeque},
     ops::Bound,
     sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use pid::Pid;
-
 use crate::{
     common::{ReadableValue, ReadableValueContainer, SensorType, SensorValue},
-    config,
+    config::{self, TimeOfDay, Weekday},
+    expression::Expression,
+    pid::PidController,
 };
 
-use log::debug;
+use log::{debug, warn};
 
 pub type CurveContainer = Arc<Mutex<dyn ReadableValue>>;
 
@@ -20,6 +21,7 @@ pub type CurveContainer = Arc<Mutex<dyn ReadableValue>>;
 pub struct LinearCurve {
     sensor: ReadableValueContainer,
     functions: BTreeMap<i32, (f32, f32)>,
+    output: SensorType,
 }
 
 //impl Curve for LinearCurve {}
@@ -47,6 +49,7 @@ impl LinearCurve {
         Self {
             sensor,
             functions: func_map,
+            output: conf.output.unwrap_or(SensorType::PERCENTAGE),
         }
     }
 }
@@ -66,7 +69,7 @@ impl ReadableValue for LinearCurve {
             m.mul_add(x, b) as i32
         });
 
-        SensorValue::new(SensorType::PERCENTAGE, 1., val as f64)
+        SensorValue::new(self.output, 1., val as f64)
     }
 }
 
@@ -108,6 +111,24 @@ impl ReadableValue for MaximumCurve {
     }
 }
 
+/// Output of another curve scaled and shifted for a member of a fan group
+pub struct ScaledCurve {
+    pub sensor: ReadableValueContainer,
+    pub scale: f64,
+    pub offset: f64,
+}
+
+impl ReadableValue for ScaledCurve {
+    fn get_value(&self) -> SensorValue {
+        let input = self.sensor.lock().unwrap().get_value();
+        SensorValue::new(
+            input.get_sensor_type(),
+            1.0,
+            input.as_scaled_value().mul_add(self.scale, self.offset),
+        )
+    }
+}
+
 pub struct AverageCurve {
     pub sensors: Vec<ReadableValueContainer>,
 }
@@ -115,65 +136,455 @@ pub struct AverageCurve {
 impl ReadableValue for AverageCurve {
     fn get_value(&self) -> SensorValue {
         let mut total: f64 = 0.;
+        let mut kind = SensorType::PERCENTAGE;
         self.sensors.iter().for_each(|val| {
-            total += val.lock().unwrap().get_value().as_scaled_value();
+            let val = val.lock().unwrap().get_value();
+            total += val.as_scaled_value();
+            // Inputs are validated to share the same type
+            kind = val.get_sensor_type();
         });
-        SensorValue::new(
-            SensorType::PERCENTAGE,
-            1.,
-            total / self.sensors.len() as f64,
-        )
+        SensorValue::new(kind, 1., total / self.sensors.len() as f64)
     }
 }
 
 pub struct PidCurve {
     sensor: ReadableValueContainer,
-    /// PID is behind a mutex to allow get_value to be immutable self
-    pid: Arc<Mutex<Pid<f32>>>,
-    last_val: i32,
+    pid: PidController,
+    /// Minimum time in seconds between two updates
+    sample_time: Option<f32>,
+    last_update: Instant,
+    last_val: f64,
 }
 
 impl PidCurve {
-    pub fn new(sensor: ReadableValueContainer, p: f32, i: f32, d: f32, target: f32) -> Self {
-        let limit = 100.0;
-        let mut pid = Pid::new(target, limit);
-        pid.p(p, limit);
-        pid.i(i, limit);
-        pid.d(d, limit);
+    pub fn new(sensor: ReadableValueContainer, conf: &config::PidCurve) -> Self {
         Self {
             sensor,
-            pid: Arc::new(Mutex::new(pid)),
-            last_val: 0,
+            pid: PidController::new(conf),
+            sample_time: conf.sample_time,
+            last_update: Instant::now(),
+            last_val: 0.0,
         }
     }
 
     pub fn set_target(&mut self, target: f32) {
-        self.pid.lock().unwrap().setpoint(target);
+        self.pid.target = target;
     }
 }
 
 impl ReadableValue for PidCurve {
     fn update_value(&mut self) {
-        let input = self.sensor.lock().unwrap().get_value().as_scaled_value() as f32;
-        let output = self.pid.lock().unwrap().next_control_output(input);
+        let now = Instant::now();
+        let elapsed = (now - self.last_update).as_secs_f32();
+        if self
+            .sample_time
+            .is_some_and(|sample_time| elapsed < sample_time)
+        {
+            return;
+        }
+        self.last_update = now;
 
-        let retval = if output.output < 0.0 {
-            -(output.output as i32)
-        } else {
-            0
-        };
+        let input = self.sensor.lock().unwrap().get_value().as_scaled_value() as f32;
+        let output = self.pid.update(input, elapsed);
 
         debug!(
-            "Pid {:?} with input {input} and target {} results in {retval}",
-            output,
-            self.pid.lock().unwrap().setpoint
+            "Pid {:?} with input {input}, target {} and dt {elapsed} results in {}",
+            output, self.pid.target, output.output
         );
 
-        self.last_val = retval;
+        self.last_val = output.output as f64;
+    }
+
+    fn get_value(&self) -> SensorValue {
+        SensorValue::new(SensorType::PERCENTAGE, 1.0, self.last_val)
+    }
+}
+
+/// Returns the current local weekday and time of day
+fn local_time() -> (Weekday, TimeOfDay) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as libc::time_t;
+    // SAFETY: tm is plain old data and localtime_r only writes into it
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&now, &mut tm) }.is_null() {
+        warn!("Couldn't determine the local time. Using UTC for schedules");
+        let days = now / 86400;
+        // 1970-01-01 was a thursday
+        tm.tm_wday = ((days + 4) % 7) as i32;
+        tm.tm_hour = (now % 86400 / 3600) as i32;
+        tm.tm_min = (now % 3600 / 60) as i32;
+    }
+    let weekday = match tm.tm_wday {
+        0 => Weekday::sun,
+        1 => Weekday::mon,
+        2 => Weekday::tue,
+        3 => Weekday::wed,
+        4 => Weekday::thu,
+        5 => Weekday::fri,
+        _ => Weekday::sat,
+    };
+    (weekday, TimeOfDay((tm.tm_hour * 60 + tm.tm_min) as u32))
+}
+
+pub struct ScheduleCurve {
+    rules: Vec<config::ScheduleRule>,
+    /// Output of every rule followed by the default
+    targets: Vec<ReadableValueContainer>,
+    crossfade: Option<Duration>,
+    /// Index into `targets` of the currently active output
+    active: usize,
+    /// Previously active output and the time of the switch. Used for the crossfade
+    previous: Option<(usize, Instant)>,
+}
+
+impl ScheduleCurve {
+    pub fn new(conf: &config::ScheduleCurve, targets: Vec<ReadableValueContainer>) -> Self {
+        Self {
+            rules: conf.rules.clone(),
+            active: targets.len() - 1,
+            targets,
+            crossfade: conf.crossfade.map(Duration::from_secs_f64),
+            previous: None,
+        }
+    }
+
+    fn update_at(&mut self, weekday: Weekday, time: TimeOfDay) {
+        let active = self
+            .rules
+            .iter()
+            .position(|rule| rule.is_active(weekday, time))
+            .unwrap_or(self.targets.len() - 1);
+        if active != self.active {
+            debug!("Schedule switched from output {} to {active}", self.active);
+            self.previous = Some((self.active, Instant::now()));
+            self.active = active;
+        }
+    }
+}
+
+impl ReadableValue for ScheduleCurve {
+    fn update_value(&mut self) {
+        let (weekday, time) = local_time();
+        self.update_at(weekday, time);
     }
 
     fn get_value(&self) -> SensorValue {
-        SensorValue::new(SensorType::PERCENTAGE, 1.0, self.last_val as f64)
+        let current = self.targets[self.active].lock().unwrap().get_value();
+        let (Some(crossfade), Some((previous, switch_time))) = (self.crossfade, self.previous)
+        else {
+            return current;
+        };
+        let fraction = switch_time.elapsed().as_secs_f64() / crossfade.as_secs_f64();
+        if fraction >= 1.0 {
+            return current;
+        }
+        let previous = self.targets[previous].lock().unwrap().get_value();
+        let val = previous.as_scaled_value()
+            + (current.as_scaled_value() - previous.as_scaled_value()) * fraction;
+        SensorValue::new(current.get_sensor_type(), 1.0, val)
+    }
+}
+
+pub struct DelayCurve {
+    sensor: ReadableValueContainer,
+    delay: Duration,
+    /// Input values with the time they were read. Oldest first
+    history: VecDeque<(Instant, SensorValue)>,
+}
+
+impl DelayCurve {
+    pub fn new(sensor: ReadableValueContainer, conf: &config::DelayCurve) -> Self {
+        Self {
+            sensor,
+            delay: Duration::from_secs_f64(conf.delay),
+            history: VecDeque::new(),
+        }
+    }
+
+    fn update_at(&mut self, now: Instant) {
+        let input = self.sensor.lock().unwrap().get_value();
+        self.history.push_back((now, input));
+        // Keep the newest value that is at least as old as the delay
+        while self
+            .history
+            .get(1)
+            .is_some_and(|(time, _)| now.duration_since(*time) >= self.delay)
+        {
+            self.history.pop_front();
+        }
+    }
+}
+
+impl ReadableValue for DelayCurve {
+    fn update_value(&mut self) {
+        self.update_at(Instant::now());
+    }
+
+    /// Returns the oldest known value until enough history is available
+    fn get_value(&self) -> SensorValue {
+        self.history.front().map_or(
+            SensorValue::new(SensorType::PERCENTAGE, 1.0, 0.0),
+            |(_, val)| *val,
+        )
+    }
+}
+
+pub struct PeakHoldCurve {
+    sensor: ReadableValueContainer,
+    window: Duration,
+    /// Decrease per second once the maximum left the window
+    decay: Option<f64>,
+    /// Input values within the window. Oldest first
+    history: VecDeque<(Instant, f64)>,
+    last_update: Option<Instant>,
+    last_val: Option<SensorValue>,
+}
+
+impl PeakHoldCurve {
+    pub fn new(sensor: ReadableValueContainer, conf: &config::PeakHoldCurve) -> Self {
+        Self {
+            sensor,
+            window: Duration::from_secs_f64(conf.window),
+            decay: conf.decay,
+            history: VecDeque::new(),
+            last_update: None,
+            last_val: None,
+        }
+    }
+
+    fn update_at(&mut self, now: Instant) {
+        let input = self.sensor.lock().unwrap().get_value();
+        self.history.push_back((now, input.as_scaled_value()));
+        while self
+            .history
+            .front()
+            .is_some_and(|(time, _)| now.duration_since(*time) > self.window)
+        {
+            self.history.pop_front();
+        }
+        let peak = self
+            .history
+            .iter()
+            .map(|(_, val)| *val)
+            .fold(f64::NEG_INFINITY, f64::max);
+        // Decay from the previously held value but never below the maximum of the window
+        let val = match (self.decay, self.last_val, self.last_update) {
+            (Some(decay), Some(last_val), Some(last_update)) => {
+                let elapsed = now.duration_since(last_update).as_secs_f64();
+                peak.max(last_val.as_scaled_value() - decay * elapsed)
+            }
+            _ => peak,
+        };
+        self.last_update = Some(now);
+        self.last_val = Some(SensorValue::new(input.get_sensor_type(), 1.0, val));
+    }
+}
+
+impl ReadableValue for PeakHoldCurve {
+    fn update_value(&mut self) {
+        self.update_at(Instant::now());
+    }
+
+    fn get_value(&self) -> SensorValue {
+        self.last_val
+            .unwrap_or(SensorValue::new(SensorType::PERCENTAGE, 1.0, 0.0))
+    }
+}
+
+pub struct DeltaCurve {
+    sensor: ReadableValueContainer,
+    ambient: ReadableValueContainer,
+    /// Time constant of the ambient smoothing
+    ambient_window: Option<Duration>,
+    /// Smoothed ambient temperature
+    ambient_val: Option<f64>,
+    last_update: Option<Instant>,
+}
+
+impl DeltaCurve {
+    pub fn new(
+        sensor: ReadableValueContainer,
+        ambient: ReadableValueContainer,
+        conf: &config::DeltaCurve,
+    ) -> Self {
+        Self {
+            sensor,
+            ambient,
+            ambient_window: conf.ambient_window.map(Duration::from_secs_f64),
+            ambient_val: None,
+            last_update: None,
+        }
+    }
+
+    fn update_at(&mut self, now: Instant) {
+        let ambient = self.ambient.lock().unwrap().get_value().as_scaled_value();
+        self.ambient_val = match (self.ambient_val, self.last_update, self.ambient_window) {
+            (Some(last), Some(last_update), Some(window)) => {
+                // Exponential moving average based on the elapsed time
+                let elapsed = now.duration_since(last_update).as_secs_f64();
+                let alpha = elapsed / (window.as_secs_f64() + elapsed);
+                Some(last + alpha * (ambient - last))
+            }
+            _ => Some(ambient),
+        };
+        self.last_update = Some(now);
+    }
+}
+
+impl ReadableValue for DeltaCurve {
+    fn update_value(&mut self) {
+        self.update_at(Instant::now());
+    }
+
+    fn get_value(&self) -> SensorValue {
+        let sensor = self.sensor.lock().unwrap().get_value().as_scaled_value();
+        let ambient = self
+            .ambient_val
+            .unwrap_or_else(|| self.ambient.lock().unwrap().get_value().as_scaled_value());
+        SensorValue::new(SensorType::TEMPERATURE, 1.0, sensor - ambient)
+    }
+}
+
+pub struct SwitchCondition {
+    sensor: ReadableValueContainer,
+    above: Option<f64>,
+    below: Option<f64>,
+    hysteresis: f64,
+    /// Whether the condition was met on the last update
+    active: bool,
+}
+
+impl SwitchCondition {
+    pub fn new(sensor: ReadableValueContainer, conf: &config::SwitchCondition) -> Self {
+        Self {
+            sensor,
+            above: conf.above,
+            below: conf.below,
+            hysteresis: conf.hysteresis,
+            active: false,
+        }
+    }
+
+    fn update(&mut self) -> bool {
+        let val = self.sensor.lock().unwrap().get_value().as_scaled_value();
+        // Once active the sensor has to pass the threshold by the hysteresis to deactivate
+        let hysteresis = if self.active { self.hysteresis } else { 0.0 };
+        self.active = match (self.above, self.below) {
+            (Some(above), _) => val > above - hysteresis,
+            (_, Some(below)) => val < below + hysteresis,
+            (None, None) => false,
+        };
+        self.active
+    }
+}
+
+pub struct SwitchCurve {
+    conditions: Vec<SwitchCondition>,
+    /// Curve of every condition followed by the default
+    targets: Vec<ReadableValueContainer>,
+    active: usize,
+}
+
+impl SwitchCurve {
+    pub fn new(conditions: Vec<SwitchCondition>, targets: Vec<ReadableValueContainer>) -> Self {
+        Self {
+            active: conditions.len(),
+            conditions,
+            targets,
+        }
+    }
+}
+
+impl ReadableValue for SwitchCurve {
+    fn update_value(&mut self) {
+        // Update every condition to keep the hysteresis state current
+        let met: Vec<bool> = self
+            .conditions
+            .iter_mut()
+            .map(|condition| condition.update())
+            .collect();
+        let active = met.iter().position(|met| *met).unwrap_or(met.len());
+        if active != self.active {
+            debug!("Switch changed from curve {} to {active}", self.active);
+            self.active = active;
+        }
+    }
+
+    fn get_value(&self) -> SensorValue {
+        self.targets[self.active].lock().unwrap().get_value()
+    }
+}
+
+pub struct ExpressionCurve {
+    pub expression: Expression,
+    pub unit: SensorType,
+    /// Every id referenced by the expression
+    pub sensors: HashMap<String, ReadableValueContainer>,
+}
+
+impl ReadableValue for ExpressionCurve {
+    fn get_value(&self) -> SensorValue {
+        let val = self.expression.evaluate(&|id| {
+            self.sensors[id]
+                .lock()
+                .unwrap()
+                .get_value()
+                .as_scaled_value()
+        });
+        SensorValue::new(self.unit, 1.0, val)
+    }
+}
+
+pub struct RampCurve {
+    sensor: ReadableValueContainer,
+    /// Maximum increase in percent per second
+    max_rise: f64,
+    /// Maximum decrease in percent per second
+    max_fall: f64,
+    last_val: Option<SensorValue>,
+    /// Time of the last update. Used to limit the change by the actual elapsed time
+    last_update: Instant,
+}
+
+impl RampCurve {
+    pub fn new(sensor: ReadableValueContainer, conf: &config::RampCurve) -> Self {
+        Self {
+            sensor,
+            max_rise: conf.max_rise,
+            max_fall: conf.max_fall,
+            last_val: None,
+            last_update: Instant::now(),
+        }
+    }
+}
+
+impl ReadableValue for RampCurve {
+    fn update_value(&mut self) {
+        let input = self.sensor.lock().unwrap().get_value();
+        let now = Instant::now();
+        let elapsed = (now - self.last_update).as_secs_f64();
+        self.last_update = now;
+
+        let val = match self.last_val {
+            Some(last_val) => {
+                let last = last_val.as_scaled_value();
+                let target = input.as_scaled_value();
+                target.clamp(
+                    last - self.max_fall * elapsed,
+                    last + self.max_rise * elapsed,
+                )
+            }
+            // Follow the input directly on the first update
+            None => input.as_scaled_value(),
+        };
+        self.last_val = Some(SensorValue::new(input.get_sensor_type(), 1.0, val));
+    }
+
+    fn get_value(&self) -> SensorValue {
+        self.last_val
+            .unwrap_or(SensorValue::new(SensorType::PERCENTAGE, 1.0, 0.0))
     }
 }
 
@@ -194,6 +605,8 @@ mod test {
         let curve_conf = config::LinearCurve {
             sensor: "test".to_string(),
             steps: curve_steps,
+            input: None,
+            output: None,
         };
         let linear_curve = LinearCurve::new(static_sensor.clone(), &curve_conf);
 
@@ -235,30 +648,325 @@ mod test {
         let avg_curve = AverageCurve { sensors };
 
         assert_eq!(avg_curve.get_value().as_scaled_value() as i32, 53);
+
+        // Average keeps the type of the inputs
+        let delta = Arc::new(Mutex::new(DeltaCurve::new(
+            Arc::new(Mutex::new(StaticCurve { value: 60 })),
+            Arc::new(Mutex::new(StaticCurve { value: 20 })),
+            &config::DeltaCurve {
+                sensor: "test".to_string(),
+                ambient: "test".to_string(),
+                ambient_window: None,
+            },
+        )));
+        let avg_curve = AverageCurve {
+            sensors: vec![delta],
+        };
+        assert_eq!(
+            avg_curve.get_value().get_sensor_type(),
+            SensorType::TEMPERATURE
+        );
+    }
+
+    #[test]
+    fn test_curve_ramp() {
+        let static_sensor = Arc::new(Mutex::new(StaticCurve { value: 30 }));
+        let curve_conf = config::RampCurve {
+            sensor: "test".to_string(),
+            max_rise: 20.0,
+            max_fall: 5.0,
+        };
+        let mut ramp_curve = RampCurve::new(static_sensor.clone(), &curve_conf);
+        ramp_curve.update_value();
+        assert_eq!(ramp_curve.get_value().as_scaled_value() as i32, 30);
+
+        // Rise is limited by the elapsed time
+        static_sensor.lock().unwrap().value = 100;
+        ramp_curve.last_update = Instant::now() - Duration::from_secs(2);
+        ramp_curve.update_value();
+        assert_eq!(ramp_curve.get_value().as_scaled_value().round() as i32, 70);
+        ramp_curve.last_update = Instant::now() - Duration::from_secs(10);
+        ramp_curve.update_value();
+        assert_eq!(ramp_curve.get_value().as_scaled_value() as i32, 100);
+
+        // Fall is slower than rise
+        static_sensor.lock().unwrap().value = 0;
+        ramp_curve.last_update = Instant::now() - Duration::from_secs(2);
+        ramp_curve.update_value();
+        assert_eq!(ramp_curve.get_value().as_scaled_value().round() as i32, 90);
+
+        // Fast loop periods barely change the value
+        ramp_curve.update_value();
+        assert_gt!(ramp_curve.get_value().as_scaled_value(), 89.0);
+    }
+
+    #[test]
+    fn test_curve_schedule() {
+        let quiet = Arc::new(Mutex::new(StaticCurve { value: 20 }));
+        let normal = Arc::new(Mutex::new(StaticCurve { value: 60 }));
+        let meeting = Arc::new(Mutex::new(StaticCurve { value: 30 }));
+        let conf = config::ScheduleCurve {
+            rules: vec![
+                config::ScheduleRule {
+                    days: vec![],
+                    from: TimeOfDay(22 * 60),
+                    to: TimeOfDay(7 * 60),
+                    target: config::ScheduleTarget::Curve("quiet".to_string()),
+                },
+                config::ScheduleRule {
+                    days: vec![Weekday::mon],
+                    from: TimeOfDay(10 * 60),
+                    to: TimeOfDay(11 * 60),
+                    target: config::ScheduleTarget::Value(30),
+                },
+            ],
+            default: config::ScheduleTarget::Curve("normal".to_string()),
+            crossfade: None,
+        };
+        let mut schedule = ScheduleCurve::new(&conf, vec![quiet, meeting, normal]);
+
+        schedule.update_at(Weekday::tue, TimeOfDay(12 * 60));
+        assert_eq!(schedule.get_value().as_scaled_value() as i32, 60);
+        schedule.update_at(Weekday::tue, TimeOfDay(23 * 60));
+        assert_eq!(schedule.get_value().as_scaled_value() as i32, 20);
+        schedule.update_at(Weekday::wed, TimeOfDay(6 * 60 + 59));
+        assert_eq!(schedule.get_value().as_scaled_value() as i32, 20);
+        schedule.update_at(Weekday::wed, TimeOfDay(7 * 60));
+        assert_eq!(schedule.get_value().as_scaled_value() as i32, 60);
+        schedule.update_at(Weekday::mon, TimeOfDay(10 * 60 + 30));
+        assert_eq!(schedule.get_value().as_scaled_value() as i32, 30);
+        schedule.update_at(Weekday::tue, TimeOfDay(10 * 60 + 30));
+        assert_eq!(schedule.get_value().as_scaled_value() as i32, 60);
+
+        // Blend between the outputs
+        schedule.crossfade = Some(Duration::from_secs(100));
+        schedule.update_at(Weekday::tue, TimeOfDay(22 * 60));
+        schedule.previous = Some((2, Instant::now() - Duration::from_secs(25)));
+        assert_eq!(schedule.get_value().as_scaled_value().round() as i32, 50);
+        schedule.previous = Some((2, Instant::now() - Duration::from_secs(100)));
+        assert_eq!(schedule.get_value().as_scaled_value() as i32, 20);
+    }
+
+    #[test]
+    fn test_curve_delay() {
+        let static_sensor = Arc::new(Mutex::new(StaticCurve { value: 10 }));
+        let curve_conf = config::DelayCurve {
+            sensor: "test".to_string(),
+            delay: 10.0,
+        };
+        let mut delay_curve = DelayCurve::new(static_sensor.clone(), &curve_conf);
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+
+        delay_curve.update_at(at(0));
+        assert_eq!(delay_curve.get_value().as_scaled_value() as i32, 10);
+        static_sensor.lock().unwrap().value = 50;
+        delay_curve.update_at(at(5));
+        assert_eq!(delay_curve.get_value().as_scaled_value() as i32, 10);
+        static_sensor.lock().unwrap().value = 80;
+        delay_curve.update_at(at(9));
+        assert_eq!(delay_curve.get_value().as_scaled_value() as i32, 10);
+        delay_curve.update_at(at(15));
+        assert_eq!(delay_curve.get_value().as_scaled_value() as i32, 50);
+        delay_curve.update_at(at(19));
+        assert_eq!(delay_curve.get_value().as_scaled_value() as i32, 80);
+        assert_eq!(delay_curve.history.len(), 3);
+    }
+
+    #[test]
+    fn test_curve_peak_hold() {
+        let static_sensor = Arc::new(Mutex::new(StaticCurve { value: 40 }));
+        let mut curve_conf = config::PeakHoldCurve {
+            sensor: "test".to_string(),
+            window: 10.0,
+            decay: None,
+        };
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+
+        let mut peak_curve = PeakHoldCurve::new(static_sensor.clone(), &curve_conf);
+        curve_conf.decay = Some(2.0);
+        let mut decay_curve = PeakHoldCurve::new(static_sensor.clone(), &curve_conf);
+        let mut update = |secs: u64, value: i32| {
+            static_sensor.lock().unwrap().value = value;
+            peak_curve.update_at(at(secs));
+            decay_curve.update_at(at(secs));
+            (
+                peak_curve.get_value().as_scaled_value() as i32,
+                decay_curve.get_value().as_scaled_value() as i32,
+            )
+        };
+
+        assert_eq!(update(0, 40), (40, 40));
+        // Short spike is held for the window
+        assert_eq!(update(1, 90), (90, 90));
+        assert_eq!(update(2, 40), (90, 90));
+        assert_eq!(update(11, 40), (90, 90));
+        // Spike left the window
+        assert_eq!(update(12, 40), (40, 88));
+        assert_eq!(update(17, 40), (40, 78));
+        assert_eq!(update(60, 40), (40, 40));
+        // Rises immediately
+        assert_eq!(update(61, 70), (70, 70));
+    }
+
+    #[test]
+    fn test_curve_delta() {
+        let cpu = Arc::new(Mutex::new(StaticCurve { value: 60 }));
+        let room = Arc::new(Mutex::new(StaticCurve { value: 20 }));
+        let mut curve_conf = config::DeltaCurve {
+            sensor: "cpu".to_string(),
+            ambient: "room".to_string(),
+            ambient_window: None,
+        };
+        let mut delta = DeltaCurve::new(cpu.clone(), room.clone(), &curve_conf);
+        curve_conf.ambient_window = Some(30.0);
+        let mut smoothed = DeltaCurve::new(cpu.clone(), room.clone(), &curve_conf);
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+
+        delta.update_at(at(0));
+        smoothed.update_at(at(0));
+        assert_eq!(delta.get_value().as_scaled_value() as i32, 40);
+        assert_eq!(smoothed.get_value().as_scaled_value() as i32, 40);
+        assert_eq!(delta.get_value().get_sensor_type(), SensorType::TEMPERATURE);
+
+        // Component changes are followed immediately
+        cpu.lock().unwrap().value = 70;
+        assert_eq!(smoothed.get_value().as_scaled_value() as i32, 50);
+
+        // Ambient changes are smoothed
+        room.lock().unwrap().value = 30;
+        delta.update_at(at(10));
+        smoothed.update_at(at(10));
+        assert_eq!(delta.get_value().as_scaled_value() as i32, 40);
+        assert_eq!(smoothed.get_value().as_scaled_value().round() as i32, 48);
+        smoothed.update_at(at(1000));
+        assert_eq!(smoothed.get_value().as_scaled_value().round() as i32, 40);
+    }
+
+    #[test]
+    fn test_curve_switch() {
+        let gpu = Arc::new(Mutex::new(StaticCurve { value: 50 }));
+        let battery = Arc::new(Mutex::new(StaticCurve { value: 1 }));
+        let aggressive = Arc::new(Mutex::new(StaticCurve { value: 100 }));
+        let battery_curve = Arc::new(Mutex::new(StaticCurve { value: 10 }));
+        let quiet = Arc::new(Mutex::new(StaticCurve { value: 30 }));
+        let condition = |sensor: ReadableValueContainer, above, below, hysteresis| {
+            let conf = config::SwitchCondition {
+                sensor: "test".to_string(),
+                above,
+                below,
+                hysteresis,
+                curve: "test".to_string(),
+            };
+            SwitchCondition::new(sensor, &conf)
+        };
+        let mut switch_curve = SwitchCurve::new(
+            vec![
+                condition(gpu.clone(), Some(70.0), None, 5.0),
+                condition(battery.clone(), None, Some(1.0), 0.0),
+            ],
+            vec![aggressive, battery_curve, quiet],
+        );
+
+        switch_curve.update_value();
+        assert_eq!(switch_curve.get_value().as_scaled_value() as i32, 30);
+        gpu.lock().unwrap().value = 71;
+        switch_curve.update_value();
+        assert_eq!(switch_curve.get_value().as_scaled_value() as i32, 100);
+        // Stays active within the hysteresis
+        gpu.lock().unwrap().value = 66;
+        switch_curve.update_value();
+        assert_eq!(switch_curve.get_value().as_scaled_value() as i32, 100);
+        gpu.lock().unwrap().value = 65;
+        switch_curve.update_value();
+        assert_eq!(switch_curve.get_value().as_scaled_value() as i32, 30);
+        // Needs to pass the threshold again to reactivate
+        gpu.lock().unwrap().value = 68;
+        switch_curve.update_value();
+        assert_eq!(switch_curve.get_value().as_scaled_value() as i32, 30);
+
+        battery.lock().unwrap().value = 0;
+        switch_curve.update_value();
+        assert_eq!(switch_curve.get_value().as_scaled_value() as i32, 10);
+        // Earlier conditions take precedence
+        gpu.lock().unwrap().value = 80;
+        switch_curve.update_value();
+        assert_eq!(switch_curve.get_value().as_scaled_value() as i32, 100);
+    }
+
+    #[test]
+    fn test_curve_expression() {
+        let cpu = Arc::new(Mutex::new(StaticCurve { value: 60 }));
+        let gpu = Arc::new(Mutex::new(StaticCurve { value: 75 }));
+        let mut sensors: HashMap<String, ReadableValueContainer> = HashMap::new();
+        sensors.insert("cpu".to_string(), cpu.clone());
+        sensors.insert("gpu".to_string(), gpu.clone());
+        let expression_curve = ExpressionCurve {
+            expression: Expression::parse("max(cpu, gpu - 10) * 1.5").unwrap(),
+            unit: SensorType::PERCENTAGE,
+            sensors,
+        };
+
+        assert_eq!(expression_curve.get_value().as_scaled_value() as i32, 97);
+        cpu.lock().unwrap().value = 70;
+        assert_eq!(expression_curve.get_value().as_scaled_value() as i32, 105);
     }
 
     #[test]
     fn test_curve_pid() {
+        // Pretend a second passed since the last update
+        fn update(pid_curve: &mut PidCurve) {
+            pid_curve.last_update = Instant::now() - Duration::from_secs(1);
+            pid_curve.update_value();
+        }
         let static_sensor = Arc::new(Mutex::new(StaticCurve { value: 0 }));
-        let mut pid_curve = PidCurve::new(static_sensor.clone(), 1.0, 1.0, 1.0, 0.0);
-        pid_curve.update_value();
+        let curve_conf = config::PidCurve {
+            sensor: "test".to_string(),
+            p: 1.0,
+            i: 1.0,
+            d: 1.0,
+            target: 0.0,
+            ..Default::default()
+        };
+        let mut pid_curve = PidCurve::new(static_sensor.clone(), &curve_conf);
+        update(&mut pid_curve);
 
         assert_eq!(pid_curve.get_value().as_scaled_value() as i32, 0);
         static_sensor.lock().unwrap().value = 100;
-        pid_curve.update_value();
+        update(&mut pid_curve);
         assert_eq!(pid_curve.get_value().as_scaled_value() as i32, 100);
 
         pid_curve.set_target(50.0);
         static_sensor.lock().unwrap().value = 10;
-        pid_curve.update_value();
+        update(&mut pid_curve);
         assert_eq!(pid_curve.get_value().as_scaled_value() as i32, 0);
 
         static_sensor.lock().unwrap().value = 49;
-        pid_curve.update_value();
+        update(&mut pid_curve);
         assert_gt!(pid_curve.get_value().as_scaled_value() as i32, 0);
 
         static_sensor.lock().unwrap().value = 51;
-        pid_curve.update_value();
+        update(&mut pid_curve);
         assert_gt!(pid_curve.get_value().as_scaled_value() as i32, 0);
     }
+
+    #[test]
+    fn test_curve_pid_sample_time() {
+        let static_sensor = Arc::new(Mutex::new(StaticCurve { value: 100 }));
+        let curve_conf = config::PidCurve {
+            sensor: "test".to_string(),
+            p: 1.0,
+            target: 50.0,
+            sample_time: Some(5.0),
+            ..Default::default()
+        };
+        let mut pid_curve = PidCurve::new(static_sensor.clone(), &curve_conf);
+        pid_curve.update_value();
+        assert_eq!(pid_curve.get_value().as_scaled_value() as i32, 0);
+        pid_curve.last_update = Instant::now() - Duration::from_secs(5);
+        pid_curve.update_value();
+        assert_eq!(pid_curve.get_value().as_scaled_value() as i32, 50);
+    }
 }
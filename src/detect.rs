@@ -0,0 +1,259 @@
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::Duration,
+};
+
+use log::{info, warn};
+
+use crate::{
+    config::{
+        CurveFunction, CurveRef, FanConfig, FanCurve, FanCurves, HwmonConfig, RufacoConfig,
+        SensorType, StaticCurve,
+    },
+    fan::{FanInput, FanOutput, PWM_ENABLE_MANUAL},
+};
+
+/// Minimum change in rpm for a tach to count as following a pwm
+const MIN_RPM_CHANGE: f64 = 100.0;
+/// Minimum change relative to full speed for a tach to count as following a pwm
+const MIN_RELATIVE_CHANGE: f64 = 0.3;
+
+pub struct PwmCandidate {
+    pub name: String,
+    pub output: Box<dyn FanOutput>,
+}
+
+pub struct TachCandidate {
+    pub name: String,
+    pub input: Box<dyn FanInput>,
+}
+
+/// Pwm output with the names of the tach inputs following it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pairing {
+    pub pwm: String,
+    pub tachs: Vec<String>,
+}
+
+fn read_tachs(tachs: &[TachCandidate]) -> Vec<Option<f64>> {
+    tachs
+        .iter()
+        .map(|tach| tach.input.get_input().ok().map(|rpm| rpm.as_rpm() as f64))
+        .collect()
+}
+
+/// Runs the pwm at full speed and stopped and returns the tachs following it.
+/// The pwm is set back to its original duty and mode afterwards
+pub fn responding_tachs(
+    pwm: &mut PwmCandidate,
+    tachs: &[TachCandidate],
+    wait: Duration,
+) -> Vec<String> {
+    let original_pwm = pwm.output.get_output();
+    let original_enable = pwm.output.get_enable();
+    pwm.output.set_enable(PWM_ENABLE_MANUAL);
+
+    pwm.output.set_output(255);
+    thread::sleep(wait);
+    let high = read_tachs(tachs);
+    pwm.output.set_output(0);
+    thread::sleep(wait);
+    let low = read_tachs(tachs);
+
+    pwm.output.set_output(original_pwm);
+    if let Some(enable) = original_enable {
+        pwm.output.set_enable(enable);
+    }
+
+    tachs
+        .iter()
+        .zip(high.iter().zip(low.iter()))
+        .filter_map(|(tach, rpms)| match rpms {
+            (Some(high), Some(low)) => {
+                let change = high - low;
+                (change > MIN_RPM_CHANGE && change > high * MIN_RELATIVE_CHANGE)
+                    .then(|| tach.name.clone())
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Drives every pwm one after another and pairs it with the tachs that respond
+pub fn detect_pairings(
+    pwms: &mut [PwmCandidate],
+    tachs: &[TachCandidate],
+    wait: Duration,
+    running: &AtomicBool,
+) -> Vec<Pairing> {
+    let mut pairings = vec![];
+    for pwm in pwms {
+        if !running.load(Ordering::SeqCst) {
+            warn!("Stop signal received. Stopping detection");
+            break;
+        }
+        info!("Testing {}", pwm.name);
+        let responding = responding_tachs(pwm, tachs, wait);
+        info!("{} drives {:?}", pwm.name, responding);
+        pairings.push(Pairing {
+            pwm: pwm.name.clone(),
+            tachs: responding,
+        });
+    }
+    pairings
+}
+
+/// Builds a config with a fan for every detected pairing, driven by a static default curve
+pub fn config_skeleton(chips: &[(String, Vec<Pairing>)]) -> RufacoConfig {
+    let fans = chips
+        .iter()
+        .flat_map(|(chip, pairings)| {
+            pairings.iter().flat_map(move |pairing| {
+                pairing.tachs.iter().map(move |tach| FanConfig {
+                    id: format!("{chip}_{tach}"),
+                    startpwm: None,
+                    minpwm: None,
+                    maxpwm: None,
+                    min_percent: None,
+                    max_percent: None,
+                    pwm_map: None,
+                    calibration: None,
+                    linearize: false,
+                    sensor: SensorType::hwmon(HwmonConfig {
+                        chip: chip.clone(),
+                        name: tach.clone(),
+                        pwm: Some(pairing.pwm.clone()),
+                    }),
+                    curve: FanCurves::Single(CurveRef::from("default")),
+                    combine: Default::default(),
+                    mode: Default::default(),
+                    rpm_control: None,
+                    shutdown: Default::default(),
+                    stall: Default::default(),
+                    start_stop: Default::default(),
+                })
+            })
+        })
+        .collect();
+    RufacoConfig {
+        sensors: vec![],
+        fans,
+        curves: vec![FanCurve {
+            id: "default".to_string(),
+            function: CurveFunction::r#static(StaticCurve { value: 50 }),
+        }],
+        fan_groups: vec![],
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use libmedium::units::AngularVelocity;
+
+    use crate::fan::{MockFanInput, MockFanOutput};
+
+    use super::*;
+
+    /// Pwm and enable values of a simulated output
+    #[derive(Default)]
+    struct OutputState {
+        pwm: u8,
+        enable: u8,
+    }
+
+    fn output(state: Arc<Mutex<OutputState>>) -> Box<dyn FanOutput> {
+        let mut output = MockFanOutput::new();
+        let set_state = state.clone();
+        output
+            .expect_set_output()
+            .returning(move |pwm| set_state.lock().unwrap().pwm = pwm);
+        let get_state = state.clone();
+        output
+            .expect_get_output()
+            .returning(move || get_state.lock().unwrap().pwm);
+        let set_state = state.clone();
+        output
+            .expect_set_enable()
+            .returning(move |enable| set_state.lock().unwrap().enable = enable);
+        output
+            .expect_get_enable()
+            .returning(move || Some(state.lock().unwrap().enable));
+        Box::new(output)
+    }
+
+    /// Tach running at 8 rpm per pwm of the output
+    fn tach(state: Arc<Mutex<OutputState>>) -> Box<dyn FanInput> {
+        let mut input = MockFanInput::new();
+        input.expect_get_input().returning(move || {
+            Ok(AngularVelocity::from_rpm(
+                state.lock().unwrap().pwm as u32 * 8,
+            ))
+        });
+        Box::new(input)
+    }
+
+    #[test]
+    fn test_detect() {
+        let first = Arc::new(Mutex::new(OutputState {
+            pwm: 100,
+            enable: 2,
+        }));
+        let second = Arc::new(Mutex::new(OutputState { pwm: 50, enable: 1 }));
+        let mut pwms = vec![
+            PwmCandidate {
+                name: "pwm1".to_string(),
+                output: output(first.clone()),
+            },
+            PwmCandidate {
+                name: "pwm2".to_string(),
+                output: output(second.clone()),
+            },
+        ];
+        // fan1 is driven by pwm2 and fan2 by pwm1
+        let tachs = vec![
+            TachCandidate {
+                name: "fan1".to_string(),
+                input: tach(second.clone()),
+            },
+            TachCandidate {
+                name: "fan2".to_string(),
+                input: tach(first.clone()),
+            },
+        ];
+        let pairings = detect_pairings(
+            &mut pwms,
+            &tachs,
+            Duration::from_nanos(0),
+            &AtomicBool::new(true),
+        );
+        assert_eq!(
+            pairings,
+            vec![
+                Pairing {
+                    pwm: "pwm1".to_string(),
+                    tachs: vec!["fan2".to_string()],
+                },
+                Pairing {
+                    pwm: "pwm2".to_string(),
+                    tachs: vec!["fan1".to_string()],
+                },
+            ]
+        );
+        // Outputs are restored
+        assert_eq!(first.lock().unwrap().pwm, 100);
+        assert_eq!(first.lock().unwrap().enable, 2);
+        assert_eq!(second.lock().unwrap().pwm, 50);
+        assert_eq!(second.lock().unwrap().enable, 1);
+
+        let conf = config_skeleton(&[("nct6798".to_string(), pairings)]);
+        conf.validate().unwrap();
+        let SensorType::hwmon(hwmon) = &conf.fans[0].sensor else {
+            panic!("Expected hwmon fan");
+        };
+        assert_eq!(conf.fans[0].id, "nct6798_fan2");
+        assert_eq!(hwmon.pwm.as_deref(), Some("pwm1"));
+    }
+}
@@ -0,0 +1,527 @@
+use std::fmt;
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Equal,
+    NotEqual,
+    And,
+    Or,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Function {
+    Max,
+    Min,
+    Avg,
+    Abs,
+    Clamp,
+    If,
+}
+
+impl Function {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "max" => Some(Function::Max),
+            "min" => Some(Function::Min),
+            "avg" => Some(Function::Avg),
+            "abs" => Some(Function::Abs),
+            "clamp" => Some(Function::Clamp),
+            "if" => Some(Function::If),
+            _ => None,
+        }
+    }
+
+    /// Returns the minimum and maximum number of arguments
+    fn arity(&self) -> (usize, usize) {
+        match self {
+            Function::Max | Function::Min | Function::Avg => (1, usize::MAX),
+            Function::Abs => (1, 1),
+            Function::Clamp | Function::If => (3, 3),
+        }
+    }
+}
+
+/// Node of a parsed expression
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Number(f64),
+    /// Reference to a sensor or curve. `column` is the 1-based position in the source
+    Ident {
+        name: String,
+        column: usize,
+    },
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    Call(Function, Vec<Expr>),
+}
+
+fn as_bool(val: f64) -> bool {
+    val != 0.0
+}
+
+fn from_bool(val: bool) -> f64 {
+    if val {
+        1.0
+    } else {
+        0.0
+    }
+}
+
+impl Expr {
+    /// Evaluates the expression. `lookup` resolves referenced ids to their current value
+    pub fn evaluate(&self, lookup: &dyn Fn(&str) -> f64) -> f64 {
+        match self {
+            Expr::Number(val) => *val,
+            Expr::Ident { name, .. } => lookup(name),
+            Expr::Unary(op, expr) => {
+                let val = expr.evaluate(lookup);
+                match op {
+                    UnaryOp::Neg => -val,
+                    UnaryOp::Not => from_bool(!as_bool(val)),
+                }
+            }
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs = lhs.evaluate(lookup);
+                let rhs = rhs.evaluate(lookup);
+                match op {
+                    BinaryOp::Add => lhs + rhs,
+                    BinaryOp::Sub => lhs - rhs,
+                    BinaryOp::Mul => lhs * rhs,
+                    BinaryOp::Div => {
+                        if rhs == 0.0 {
+                            0.0
+                        } else {
+                            lhs / rhs
+                        }
+                    }
+                    BinaryOp::Less => from_bool(lhs < rhs),
+                    BinaryOp::LessEqual => from_bool(lhs <= rhs),
+                    BinaryOp::Greater => from_bool(lhs > rhs),
+                    BinaryOp::GreaterEqual => from_bool(lhs >= rhs),
+                    BinaryOp::Equal => from_bool(lhs == rhs),
+                    BinaryOp::NotEqual => from_bool(lhs != rhs),
+                    BinaryOp::And => from_bool(as_bool(lhs) && as_bool(rhs)),
+                    BinaryOp::Or => from_bool(as_bool(lhs) || as_bool(rhs)),
+                }
+            }
+            Expr::Call(function, args) => {
+                let mut vals = args.iter().map(|arg| arg.evaluate(lookup));
+                match function {
+                    Function::Max => vals.fold(f64::NEG_INFINITY, f64::max),
+                    Function::Min => vals.fold(f64::INFINITY, f64::min),
+                    Function::Avg => vals.sum::<f64>() / args.len() as f64,
+                    Function::Abs => vals.next().unwrap().abs(),
+                    Function::Clamp => {
+                        let val = vals.next().unwrap();
+                        let low = vals.next().unwrap();
+                        let high = vals.next().unwrap();
+                        val.max(low).min(high)
+                    }
+                    Function::If => {
+                        // Only evaluate the selected branch
+                        if as_bool(args[0].evaluate(lookup)) {
+                            args[1].evaluate(lookup)
+                        } else {
+                            args[2].evaluate(lookup)
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn collect_identifiers<'a>(&'a self, ids: &mut Vec<(&'a str, usize)>) {
+        match self {
+            Expr::Number(_) => {}
+            Expr::Ident { name, column } => ids.push((name, *column)),
+            Expr::Unary(_, expr) => expr.collect_identifiers(ids),
+            Expr::Binary(_, lhs, rhs) => {
+                lhs.collect_identifiers(ids);
+                rhs.collect_identifiers(ids);
+            }
+            Expr::Call(_, args) => args.iter().for_each(|arg| arg.collect_identifiers(ids)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    /// 1-based column of the offending token
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "column {}: {}", self.column, self.message)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Ident(String),
+    Op(&'static str),
+    LParen,
+    RParen,
+    Comma,
+    End,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Number(val) => write!(f, "`{val}`"),
+            Token::Ident(name) => write!(f, "`{name}`"),
+            Token::Op(op) => write!(f, "`{op}`"),
+            Token::LParen => write!(f, "`(`"),
+            Token::RParen => write!(f, "`)`"),
+            Token::Comma => write!(f, "`,`"),
+            Token::End => write!(f, "end of expression"),
+        }
+    }
+}
+
+const OPERATORS: [&str; 14] = [
+    "<=", ">=", "==", "!=", "&&", "||", "<", ">", "+", "-", "*", "/", "!", "=",
+];
+
+/// Splits the source into tokens paired with their 1-based column
+fn tokenize(source: &str) -> Result<Vec<(Token, usize)>, ParseError> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = vec![];
+    let mut pos = 0;
+    while pos < chars.len() {
+        let c = chars[pos];
+        let column = pos + 1;
+        if c.is_whitespace() {
+            pos += 1;
+        } else if c.is_ascii_digit() || c == '.' {
+            let start = pos;
+            while pos < chars.len() && (chars[pos].is_ascii_digit() || chars[pos] == '.') {
+                pos += 1;
+            }
+            let text: String = chars[start..pos].iter().collect();
+            let val = text.parse::<f64>().map_err(|_| ParseError {
+                column,
+                message: format!("invalid number `{text}`"),
+            })?;
+            tokens.push((Token::Number(val), column));
+        } else if c.is_alphabetic() || c == '_' {
+            let start = pos;
+            while pos < chars.len()
+                && (chars[pos].is_alphanumeric() || chars[pos] == '_' || chars[pos] == '.')
+            {
+                pos += 1;
+            }
+            tokens.push((Token::Ident(chars[start..pos].iter().collect()), column));
+        } else if c == '(' {
+            tokens.push((Token::LParen, column));
+            pos += 1;
+        } else if c == ')' {
+            tokens.push((Token::RParen, column));
+            pos += 1;
+        } else if c == ',' {
+            tokens.push((Token::Comma, column));
+            pos += 1;
+        } else {
+            let rest: String = chars[pos..chars.len().min(pos + 2)].iter().collect();
+            let op = OPERATORS
+                .iter()
+                .find(|op| rest.starts_with(*op))
+                .ok_or_else(|| ParseError {
+                    column,
+                    message: format!("unexpected character `{c}`"),
+                })?;
+            if *op == "=" {
+                return Err(ParseError {
+                    column,
+                    message: "unexpected `=`. Did you mean `==`?".to_string(),
+                });
+            }
+            tokens.push((Token::Op(op), column));
+            pos += op.len();
+        }
+    }
+    tokens.push((Token::End, chars.len() + 1));
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+}
+
+/// Binary operators grouped by precedence, lowest first
+const PRECEDENCE: [&[(&str, BinaryOp)]; 5] = [
+    &[("||", BinaryOp::Or)],
+    &[("&&", BinaryOp::And)],
+    &[
+        ("<", BinaryOp::Less),
+        ("<=", BinaryOp::LessEqual),
+        (">", BinaryOp::Greater),
+        (">=", BinaryOp::GreaterEqual),
+        ("==", BinaryOp::Equal),
+        ("!=", BinaryOp::NotEqual),
+    ],
+    &[("+", BinaryOp::Add), ("-", BinaryOp::Sub)],
+    &[("*", BinaryOp::Mul), ("/", BinaryOp::Div)],
+];
+
+impl Parser {
+    fn peek(&self) -> &(Token, usize) {
+        &self.tokens[self.pos]
+    }
+
+    fn next(&mut self) -> (Token, usize) {
+        let token = self.tokens[self.pos].clone();
+        if token.0 != Token::End {
+            self.pos += 1;
+        }
+        token
+    }
+
+    fn unexpected(&self) -> ParseError {
+        let (token, column) = self.peek();
+        ParseError {
+            column: *column,
+            message: format!("unexpected {token}"),
+        }
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), ParseError> {
+        if self.peek().0 == expected {
+            self.next();
+            Ok(())
+        } else {
+            let (token, column) = self.peek();
+            Err(ParseError {
+                column: *column,
+                message: format!("expected {expected} but found {token}"),
+            })
+        }
+    }
+
+    fn parse_binary(&mut self, level: usize) -> Result<Expr, ParseError> {
+        if level >= PRECEDENCE.len() {
+            return self.parse_unary();
+        }
+        let mut lhs = self.parse_binary(level + 1)?;
+        while let Token::Op(op) = self.peek().0 {
+            let Some((_, bin_op)) = PRECEDENCE[level].iter().find(|(name, _)| *name == op) else {
+                break;
+            };
+            self.next();
+            let rhs = self.parse_binary(level + 1)?;
+            lhs = Expr::Binary(*bin_op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, ParseError> {
+        match self.peek().0 {
+            Token::Op("-") => {
+                self.next();
+                Ok(Expr::Unary(UnaryOp::Neg, Box::new(self.parse_unary()?)))
+            }
+            Token::Op("!") => {
+                self.next();
+                Ok(Expr::Unary(UnaryOp::Not, Box::new(self.parse_unary()?)))
+            }
+            _ => self.parse_primary(),
+        }
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, ParseError> {
+        match self.peek().0.clone() {
+            Token::Number(val) => {
+                self.next();
+                Ok(Expr::Number(val))
+            }
+            Token::LParen => {
+                self.next();
+                let expr = self.parse_binary(0)?;
+                self.expect(Token::RParen)?;
+                Ok(expr)
+            }
+            Token::Ident(name) => {
+                let (_, column) = self.next();
+                if self.peek().0 == Token::LParen {
+                    self.parse_call(&name, column)
+                } else {
+                    Ok(Expr::Ident { name, column })
+                }
+            }
+            _ => Err(self.unexpected()),
+        }
+    }
+
+    fn parse_call(&mut self, name: &str, column: usize) -> Result<Expr, ParseError> {
+        let function = Function::from_name(name).ok_or_else(|| ParseError {
+            column,
+            message: format!("unknown function `{name}`"),
+        })?;
+        self.expect(Token::LParen)?;
+        let mut args = vec![];
+        if self.peek().0 != Token::RParen {
+            loop {
+                args.push(self.parse_binary(0)?);
+                if self.peek().0 != Token::Comma {
+                    break;
+                }
+                self.next();
+            }
+        }
+        self.expect(Token::RParen)?;
+        let (min_args, max_args) = function.arity();
+        if args.len() < min_args || args.len() > max_args {
+            let expected = if min_args == max_args {
+                format!("{min_args}")
+            } else {
+                format!("at least {min_args}")
+            };
+            return Err(ParseError {
+                column,
+                message: format!(
+                    "function `{name}` expects {expected} arguments but got {}",
+                    args.len()
+                ),
+            });
+        }
+        Ok(Expr::Call(function, args))
+    }
+}
+
+/// An expression parsed from its config representation
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expression {
+    source: String,
+    ast: Expr,
+}
+
+impl Expression {
+    pub fn parse(source: &str) -> Result<Self, ParseError> {
+        let mut parser = Parser {
+            tokens: tokenize(source)?,
+            pos: 0,
+        };
+        let ast = parser.parse_binary(0)?;
+        if parser.peek().0 != Token::End {
+            return Err(parser.unexpected());
+        }
+        Ok(Self {
+            source: source.to_string(),
+            ast,
+        })
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Returns every referenced id together with the column it appears at
+    pub fn identifiers(&self) -> Vec<(&str, usize)> {
+        let mut ids = vec![];
+        self.ast.collect_identifiers(&mut ids);
+        ids
+    }
+
+    pub fn evaluate(&self, lookup: &dyn Fn(&str) -> f64) -> f64 {
+        self.ast.evaluate(lookup)
+    }
+}
+
+impl Serialize for Expression {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.source)
+    }
+}
+
+impl<'de> Deserialize<'de> for Expression {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let source = String::deserialize(deserializer)?;
+        Expression::parse(&source).map_err(|err| {
+            de::Error::custom(format!(
+                "invalid expression at {err}\n  {source}\n  {:>width$}",
+                "^",
+                width = err.column
+            ))
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn eval(source: &str) -> f64 {
+        Expression::parse(source).unwrap().evaluate(&|id| match id {
+            "cpu" => 60.0,
+            "gpu" => 75.0,
+            "fan.rpm" => 1200.0,
+            _ => panic!("Unknown id {id}"),
+        })
+    }
+
+    #[test]
+    fn test_expression_eval() {
+        assert_eq!(eval("1 + 2 * 3"), 7.0);
+        assert_eq!(eval("(1 + 2) * 3"), 9.0);
+        assert_eq!(eval("-cpu + 10"), -50.0);
+        assert_eq!(eval("max(cpu, gpu - 10) * 1.2"), 78.0);
+        assert_eq!(eval("min(cpu, gpu, 5)"), 5.0);
+        assert_eq!(eval("avg(cpu, gpu)"), 67.5);
+        assert_eq!(eval("abs(cpu - gpu)"), 15.0);
+        assert_eq!(eval("clamp(gpu, 0, 70)"), 70.0);
+        assert_eq!(eval("if(gpu > 70, 100, 30)"), 100.0);
+        assert_eq!(eval("if(gpu > 70 && cpu > 70, 100, 30)"), 30.0);
+        assert_eq!(eval("if(!(cpu >= 60) || cpu == 60, 1, 0)"), 1.0);
+        assert_eq!(eval("fan.rpm / 12"), 100.0);
+        assert_eq!(eval("cpu / 0"), 0.0);
+    }
+
+    #[test]
+    fn test_expression_identifiers() {
+        let expression = Expression::parse("max(cpu, gpu - 10) + cpu").unwrap();
+        assert_eq!(
+            expression.identifiers(),
+            vec![("cpu", 5), ("gpu", 10), ("cpu", 22)]
+        );
+    }
+
+    #[test]
+    fn test_expression_errors() {
+        let err = Expression::parse("max(cpu,, gpu)").unwrap_err();
+        assert_eq!(err.column, 9);
+        let err = Expression::parse("cpu +").unwrap_err();
+        assert_eq!(err.column, 6);
+        let err = Expression::parse("foo(cpu)").unwrap_err();
+        assert_eq!(err.column, 1);
+        let err = Expression::parse("clamp(cpu, 1)").unwrap_err();
+        assert_eq!(err.column, 1);
+        let err = Expression::parse("cpu = 1").unwrap_err();
+        assert_eq!(err.column, 5);
+        let err = Expression::parse("(cpu").unwrap_err();
+        assert_eq!(err.column, 5);
+        let err = Expression::parse("cpu gpu").unwrap_err();
+        assert_eq!(err.column, 5);
+        let err = Expression::parse("cpu $ 2").unwrap_err();
+        assert_eq!(err.column, 5);
+    }
+}
@@ -1,6 +1,7 @@
 use std::{
-    collections::VecDeque,
+    collections::{BTreeMap, VecDeque},
     error::Error,
+    fmt,
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc, Mutex,
@@ -17,8 +18,12 @@ use log::*;
 
 use crate::{
     common::{ReadableValue, SensorType, SensorValue, UpdatableInput, UpdatableOutput},
-    config::FanConfig,
+    config::{
+        self, CombineRule, FanCalibration, FanConfig, FanMode, FanReading, RpmControl,
+        ShutdownState, StallConfig, StartStopConfig,
+    },
     curve::CurveContainer,
+    pid::PidController,
 };
 
 #[cfg(test)]
@@ -28,25 +33,33 @@ use mockall::automock;
 pub type FanContainer = Arc<Mutex<FanSensor>>;
 
 pub struct HwmonFan {
-    pub fan_input: Box<dyn WriteableFanSensor>,
+    pub fan_input: Box<dyn WriteableFanSensor + Send>,
 }
 
 pub struct HwmonPwm {
-    pub fan_pwm: Box<dyn WriteablePwmSensor>,
+    pub fan_pwm: Box<dyn WriteablePwmSensor + Send>,
 }
 
 #[cfg_attr(test, automock)]
-pub trait FanInput {
+pub trait FanInput: Send {
     fn get_input(&self) -> Result<AngularVelocity, Box<dyn Error>>;
 }
 
 #[cfg_attr(test, automock)]
-pub trait FanOutput {
+pub trait FanOutput: Send {
     /// Set the fan output to pwm
     fn set_output(&mut self, pwm: u8);
 
     /// Get the currently set value
     fn get_output(&self) -> u8;
+
+    /// Get the raw pwm_enable value. None if the output has no mode
+    fn get_enable(&self) -> Option<u8> {
+        None
+    }
+
+    /// Set the raw pwm_enable value. 1 is manual control
+    fn set_enable(&mut self, _enable: u8) {}
 }
 
 impl FanOutput for HwmonPwm {
@@ -57,6 +70,30 @@ impl FanOutput for HwmonPwm {
     fn get_output(&self) -> u8 {
         self.fan_pwm.read_pwm().unwrap().as_u8()
     }
+
+    // libmedium only knows three modes, so the raw value is used to restore e.g. automatic
+    // modes of Super I/O chips exactly
+    fn get_enable(&self) -> Option<u8> {
+        let raw = std::fs::read_to_string(self.enable_path()).ok()?;
+        raw.trim().parse().ok()
+    }
+
+    fn set_enable(&mut self, enable: u8) {
+        if let Err(err) = std::fs::write(self.enable_path(), enable.to_string()) {
+            error!(
+                "Failed to set {} to {enable}: {err}",
+                self.enable_path().display()
+            );
+        }
+    }
+}
+
+impl HwmonPwm {
+    fn enable_path(&self) -> std::path::PathBuf {
+        self.fan_pwm
+            .hwmon_path()
+            .join(format!("pwm{}_enable", self.fan_pwm.index()))
+    }
 }
 
 impl FanInput for HwmonFan {
@@ -69,110 +106,749 @@ impl FanInput for HwmonFan {
     }
 }
 
+/// Latest values of a fan shared with the curves using the fan as input
+#[derive(Default, Debug, Clone, Copy)]
+pub struct FanReadings {
+    pub rpm: u32,
+    pub pwm: u8,
+    pub percent: f64,
+}
+
+pub type FanReadingsContainer = Arc<Mutex<FanReadings>>;
+
+/// A single reading of a fan used as curve input
+pub struct FanReadingValue {
+    pub readings: FanReadingsContainer,
+    pub reading: FanReading,
+}
+
+impl ReadableValue for FanReadingValue {
+    fn get_value(&self) -> SensorValue {
+        let readings = self.readings.lock().unwrap();
+        match self.reading {
+            FanReading::rpm => SensorValue::new(SensorType::RPM, 1.0, readings.rpm as f64),
+            FanReading::pwm => {
+                SensorValue::new(SensorType::PERCENTAGE, 100.0 / 255.0, readings.pwm as f64)
+            }
+            FanReading::percent => SensorValue::new(SensorType::PERCENTAGE, 1.0, readings.percent),
+        }
+    }
+}
+
+/// Regulates the percentage of a fan in rpm mode to reach the target rpm
+pub struct RpmController {
+    pid: PidController,
+    /// Maximum change of the output in percent per second
+    max_rate: f64,
+    max_rpm: Option<u32>,
+    /// Last output in percent
+    output: f64,
+    last_update: time::Instant,
+}
+
+impl RpmController {
+    pub fn new(conf: &RpmControl) -> Self {
+        let pid = PidController::new(&config::PidCurve {
+            p: conf.p,
+            i: conf.i,
+            direction: config::PidDirection::heating,
+            ..Default::default()
+        });
+        Self {
+            pid,
+            max_rate: conf.max_rate as f64,
+            max_rpm: conf.max_rpm,
+            output: 0.0,
+            last_update: time::Instant::now(),
+        }
+    }
+
+    /// Returns the percentage to reach the target. Runs open-loop if the rpm is unknown.
+    /// `dt` is the time in seconds since the last update
+    fn update(&mut self, target: f64, rpm: Option<u32>, dt: f64) -> f64 {
+        let Some(rpm) = rpm else {
+            // Without a max rpm full speed is the only safe choice
+            self.output = self
+                .max_rpm
+                .map_or(100.0, |max_rpm| target / max_rpm as f64 * 100.0)
+                .clamp(0.0, 100.0);
+            return self.output;
+        };
+        self.pid.target = target as f32;
+        let output = self.pid.update(rpm as f32, dt as f32).output as f64;
+        let max_change = self.max_rate * dt;
+        self.output += (output - self.output).clamp(-max_change, max_change);
+        self.output
+    }
+}
+
+/// pwm_enable value for manual control
+pub const PWM_ENABLE_MANUAL: u8 = 1;
+/// pwm_enable value for automatic control. Chips may use higher values for further auto modes
+pub const PWM_ENABLE_AUTO: u8 = 2;
+/// Min pwm of uncalibrated fans. High enough to keep most fans spinning
+const DEFAULT_MIN_PWM: u8 = 64;
+/// Start pwm of uncalibrated fans. High enough to start most fans
+const DEFAULT_START_PWM: u8 = 128;
+/// Pwm step between the points of the calibration sweep
+const SWEEP_STEP: u8 = 16;
+/// Relative deviation from the calibrated rpm at which a fan is reported as unhealthy
+const HEALTH_TOLERANCE: f64 = 0.3;
+
+/// When the rpm of a fan counts as settled during calibration
+#[derive(Debug, Clone)]
+pub struct SettleCriteria {
+    /// Time between two rpm readings
+    pub interval: Duration,
+    /// Number of readings that have to be within the tolerance
+    pub window: usize,
+    /// Maximum deviation of a reading from the mean of the window in rpm
+    pub tolerance: i32,
+    /// Maximum time to wait for a single pwm to settle
+    pub timeout: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CalibrationError {
+    /// The stop signal was received
+    Stopped,
+    /// The rpm didn't settle within the timeout
+    Unsettled { pwm: u8, rpms: Vec<i32> },
+    /// The fan has no tach to measure
+    NoTach,
+}
+
+impl fmt::Display for CalibrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CalibrationError::Stopped => write!(f, "calibration was stopped"),
+            CalibrationError::Unsettled { pwm, rpms } => {
+                write!(f, "rpm didn't settle at pwm {pwm}. Last readings: {rpms:?}")
+            }
+            CalibrationError::NoTach => write!(f, "fan has no tach"),
+        }
+    }
+}
+
+/// Result of [FanSensor::measure_fan]
+#[derive(Debug, Clone, PartialEq)]
+pub struct FanMeasurement {
+    pub min_pwm: u8,
+    pub start_pwm: u8,
+    pub calibration: FanCalibration,
+}
+
+impl FanCalibration {
+    /// Interpolated rpm at the pwm
+    pub fn expected_rpm(&self, pwm: u8) -> f64 {
+        let below = self.rpm.range(..=pwm).next_back();
+        let above = self.rpm.range(pwm..).next();
+        match (below, above) {
+            (Some((&x0, &y0)), Some((&x1, &y1))) if x1 != x0 => {
+                y0 as f64 + (y1 as f64 - y0 as f64) * (pwm - x0) as f64 / (x1 - x0) as f64
+            }
+            (Some((_, &rpm)), _) | (None, Some((_, &rpm))) => rpm as f64,
+            (None, None) => 0.0,
+        }
+    }
+
+    /// Lowest pwm reaching the rpm, interpolated between the measured points
+    pub fn pwm_for_rpm(&self, rpm: f64) -> u8 {
+        let mut points = self.rpm.iter();
+        let Some(mut low) = points.next() else {
+            return 255;
+        };
+        if rpm <= *low.1 as f64 {
+            return *low.0;
+        }
+        for high in points {
+            if rpm <= *high.1 as f64 {
+                let (x0, y0, x1, y1) =
+                    (*low.0 as f64, *low.1 as f64, *high.0 as f64, *high.1 as f64);
+                let pwm = if y1 > y0 {
+                    x0 + (rpm - y0) * (x1 - x0) / (y1 - y0)
+                } else {
+                    x0
+                };
+                return pwm.ceil() as u8;
+            }
+            low = high;
+        }
+        255
+    }
+
+    /// Pwm for a percentage of the rpm range between the slowest spinning point and `max_rpm`
+    pub fn pwm_for_percentage(&self, percentage: f64) -> u8 {
+        let min_rpm = self
+            .rpm
+            .values()
+            .find(|rpm| **rpm > 0)
+            .map_or(0.0, |rpm| *rpm as f64);
+        let rpm = (percentage / 100.0).mul_add(self.max_rpm as f64 - min_rpm, min_rpm);
+        self.pwm_for_rpm(rpm)
+    }
+}
+
+/// Stall tracking of a fan with tach
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StallState {
+    Running,
+    /// Driven above the start pwm without rpm since the instant
+    Stalled(time::Instant),
+    /// Driven at full speed until the instant to recover
+    Kicking(time::Instant),
+    /// Recovery failed. The fan stays in this state until it spins again
+    Failed,
+}
+
+/// Change of the [StallState] in an update
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StallEvent {
+    Stalled,
+    Kick {
+        attempt: u32,
+    },
+    Recovered,
+    /// All kicks failed
+    Failed,
+}
+
+/// Snapshot of a fan for the status output
+#[derive(Debug, Clone, PartialEq)]
+pub struct FanStatus {
+    pub id: String,
+    /// None for fans without tach
+    pub rpm: Option<u32>,
+    pub pwm: u8,
+    pub percent: f64,
+    /// Output of the curves before the percentage clamps
+    pub curve_percent: f64,
+    pub max_pwm: u8,
+    /// Percentage added for failed members of the fan's group
+    pub group_boost: f64,
+    /// Curve determining the output. None if all curves contribute
+    pub active_curve: Option<String>,
+    /// Target of fans in rpm mode
+    pub target_rpm: Option<f64>,
+    /// Rpm expected from the calibration at the current pwm
+    pub expected_rpm: Option<f64>,
+    pub stall_state: StallState,
+}
+
+impl fmt::Display for FanStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.rpm {
+            Some(rpm) => write!(f, "{}: {rpm} rpm", self.id)?,
+            None => write!(f, "{}: no tach", self.id)?,
+        }
+        if let Some(target_rpm) = self.target_rpm {
+            write!(f, " (target {target_rpm:.0})")?;
+        }
+        if let Some(expected_rpm) = self.expected_rpm {
+            write!(f, " (expected {expected_rpm:.0})")?;
+        }
+        write!(f, ", pwm {}", self.pwm)?;
+        if self.max_pwm < 255 {
+            write!(f, "/{}", self.max_pwm)?;
+        }
+        write!(f, ", {:.1}%", self.percent)?;
+        if (self.curve_percent - self.percent).abs() >= 0.05 {
+            write!(f, " (clamped from {:.1}%)", self.curve_percent)?;
+        }
+        write!(
+            f,
+            " from {}",
+            self.active_curve.as_deref().unwrap_or("average")
+        )?;
+        if self.group_boost > 0.0 {
+            write!(
+                f,
+                ", boosted by {:.0}% for failed group members",
+                self.group_boost
+            )?;
+        }
+        match self.stall_state {
+            StallState::Running => Ok(()),
+            StallState::Stalled(_) => write!(f, ", stalled"),
+            StallState::Kicking(_) => write!(f, ", kicking"),
+            StallState::Failed => write!(f, ", FAILED"),
+        }
+    }
+}
+
 pub struct FanSensor {
     pub id: String,
-    /// The fan input sensor
-    pub fan_input: Box<dyn FanInput>,
+    /// The fan input sensor. None for fans without tach
+    pub fan_input: Option<Box<dyn FanInput>>,
     /// The pwm output of the fan
     pub fan_pwm: Box<dyn FanOutput>,
-    pub curve: CurveContainer,
+    /// Curves driving the fan with their ids
+    pub curves: Vec<(String, CurveContainer)>,
+    pub combine: CombineRule,
+    /// Curve currently determining the output. None if all curves contribute
+    pub active_curve: Option<String>,
     pub last_val: u32,
+    /// Whether the last read of the fan input failed
+    pub input_error: bool,
+    /// Set in rpm mode
+    pub rpm_controller: Option<RpmController>,
+    target_rpm: Option<f64>,
+    /// Values shared with curves that use this fan as input
+    pub readings: FanReadingsContainer,
+    pub calibration: Option<FanCalibration>,
+    /// Map percentages onto the calibrated rpm range
+    pub linearize: bool,
+    /// Whether the rpm deviated from the calibration in the last update
+    unhealthy: bool,
     /// Min PWM to keep the fan spinning
     pub min_pwm: u8,
     /// PWM to start the fan
     pub start_pwm: u8,
+    /// Highest pwm written outside of kicks and spin-up pulses
+    pub max_pwm: u8,
+    pub min_percent: f64,
+    pub max_percent: f64,
+    pub pwm_map: Option<BTreeMap<u8, u8>>,
+    /// Output of the curves before the clamps
+    curve_percent: f64,
+    pub shutdown: ShutdownState,
+    pub stall: StallConfig,
+    pub stall_state: StallState,
+    /// Kicks since the fan last ran
+    kicks: u32,
+    /// pwm_enable and pwm of the output before rufaco took control
+    original_output: Option<(Option<u8>, u8)>,
 
-    /// Value the fan should start spinning at. Fan still spins below this value if it was spinning
-    /// previously
-    pub start_percent: f64,
-    /// Time the fan is at 0%. Only after staying for 10 sec at 0% the fan is turned off. Used to prevent spin up and down loop
+    pub start_stop: StartStopConfig,
+    /// Time the fan is below the stop percentage. The fan is turned off after the stop delay
     zero_percent_time: Option<time::Instant>,
+    /// Whether the fan was last switched on
+    powered: bool,
+    /// Time the fan was last switched on or off
+    last_switch: Option<time::Instant>,
+    /// Set if a member of the fan's group runs. Starts the fan below its start percentage
+    pub group_running: bool,
+    /// Percentage added to compensate for failed members of the fan's group
+    pub group_boost: f64,
+    /// End of the spin-up pulse
+    spin_up_until: Option<time::Instant>,
 }
 
 impl FanSensor {
     pub fn new(
         conf: &FanConfig,
-        fan_input: Box<dyn FanInput>,
+        fan_input: Option<Box<dyn FanInput>>,
         fan_pwm: Box<dyn FanOutput>,
-        curve: CurveContainer,
+        curves: Vec<(String, CurveContainer)>,
+        readings: FanReadingsContainer,
     ) -> Self {
-        let min_pwm = conf.minpwm.unwrap_or(0);
-        let start_pwm = conf.startpwm.unwrap_or(0);
+        if fan_input.is_none() {
+            info!(
+                "Fan {} has no tach. Rpm based features are disabled",
+                conf.id
+            );
+        }
+        if conf.minpwm.is_none() || conf.startpwm.is_none() {
+            warn!(
+                "Fan {} is not calibrated. Using a min pwm of {DEFAULT_MIN_PWM} and start pwm of {DEFAULT_START_PWM}",
+                conf.id
+            );
+        }
+        let min_pwm = conf.minpwm.unwrap_or(DEFAULT_MIN_PWM);
+        let start_pwm = conf.startpwm.unwrap_or(DEFAULT_START_PWM);
         Self {
             id: conf.id.clone(),
             fan_input,
             fan_pwm,
-            curve,
+            curves,
+            combine: conf.combine,
+            active_curve: None,
             last_val: 0,
+            input_error: false,
+            rpm_controller: match conf.mode {
+                FanMode::percent => None,
+                FanMode::rpm => Some(RpmController::new(
+                    &conf.rpm_control.clone().unwrap_or_default(),
+                )),
+            },
+            target_rpm: None,
+            calibration: conf.calibration.clone(),
+            linearize: conf.linearize,
+            unhealthy: false,
+            readings,
             min_pwm,
             start_pwm,
-            start_percent: 20.0,
+            max_pwm: conf.maxpwm.unwrap_or(255),
+            min_percent: conf.min_percent.unwrap_or(0.0),
+            max_percent: conf.max_percent.unwrap_or(100.0),
+            pwm_map: conf.pwm_map.clone(),
+            curve_percent: 0.0,
+            shutdown: conf.shutdown,
+            stall: conf.stall.clone(),
+            stall_state: StallState::Running,
+            kicks: 0,
+            original_output: None,
+            start_stop: conf.start_stop.clone(),
             zero_percent_time: None,
+            powered: false,
+            last_switch: None,
+            group_running: false,
+            group_boost: 0.0,
+            spin_up_until: None,
         }
     }
 }
 
 impl FanSensor {
+    /// Remembers the mode and duty of the output and switches it to manual control
+    pub fn take_control(&mut self) {
+        let enable = self.fan_pwm.get_enable();
+        let pwm = self.fan_pwm.get_output();
+        debug!("Fan {} had pwm_enable {enable:?} and pwm {pwm}", self.id);
+        self.original_output = Some((enable, pwm));
+        self.fan_pwm.set_enable(PWM_ENABLE_MANUAL);
+    }
+
+    /// Puts the output into the configured shutdown state if rufaco took control of it
+    pub fn release(&mut self) {
+        let Some((enable, pwm)) = self.original_output.take() else {
+            return;
+        };
+        match self.shutdown {
+            ShutdownState::restore => {
+                self.fan_pwm.set_output(pwm);
+                if let Some(enable) = enable {
+                    self.fan_pwm.set_enable(enable);
+                }
+            }
+            ShutdownState::full_speed => self.fan_pwm.set_output(255),
+            ShutdownState::auto => self.fan_pwm.set_enable(
+                enable
+                    .filter(|enable| *enable >= PWM_ENABLE_AUTO)
+                    .unwrap_or(PWM_ENABLE_AUTO),
+            ),
+            ShutdownState::last => {}
+        }
+        info!("Released fan {} to {:?}", self.id, self.shutdown);
+    }
+
+    /// Whether the tach shows the fan stalled or can't be read
+    pub fn has_failed(&self) -> bool {
+        self.has_tach() && (self.input_error || self.stall_state != StallState::Running)
+    }
+
+    /// Whether the fan was last switched on
+    pub fn is_powered(&self) -> bool {
+        self.powered
+    }
+
+    pub fn has_tach(&self) -> bool {
+        self.fan_input.is_some()
+    }
+
+    /// Whether the fan spins. Without tach the fan is assumed to spin whenever a pwm is applied
     pub fn is_spinning(&self) -> bool {
-        self.get_value().as_scaled_value() >= 1.0
+        match self.fan_input {
+            Some(_) => self.get_value().as_scaled_value() >= 1.0,
+            None => self.readings.lock().unwrap().pwm > 0,
+        }
+    }
+
+    /// Maps the percentage onto the pwm range between `min_pwm` and `max_pwm`
+    fn percentage_to_pwm(percentage: f64, min_pwm: u8, max_pwm: u8) -> u8 {
+        let pwm_range = max_pwm - min_pwm;
+        (percentage / 100.0).mul_add(pwm_range as f64, min_pwm as f64) as u8
+    }
+
+    /// Pwm of the percentage interpolated between the points of the map
+    fn map_percentage(map: &BTreeMap<u8, u8>, percentage: f64) -> u8 {
+        let percent = percentage.clamp(0.0, 100.0);
+        let below = map.range(..=percent.floor() as u8).next_back();
+        let above = map.range(percent.ceil() as u8..).next();
+        match (below, above) {
+            (Some((&x0, &y0)), Some((&x1, &y1))) if x1 != x0 => {
+                (y0 as f64 + (y1 as f64 - y0 as f64) * (percent - x0 as f64) / (x1 - x0) as f64)
+                    as u8
+            }
+            (Some((_, &pwm)), _) | (None, Some((_, &pwm))) => pwm,
+            (None, None) => 0,
+        }
+    }
+
+    /// Sets the fan to the percentage directly without the curve and stop delay
+    pub fn set_percentage(&mut self, percentage: f64) {
+        self.update_input();
+        let min_pwm = if self.is_spinning() {
+            self.min_pwm
+        } else {
+            self.start_pwm
+        };
+        self.set_pwm(percentage, self.pwm_for_percentage(percentage, min_pwm));
+    }
+
+    pub fn status(&self) -> FanStatus {
+        let readings = self.readings.lock().unwrap();
+        FanStatus {
+            id: self.id.clone(),
+            rpm: self.has_tach().then_some(readings.rpm),
+            pwm: readings.pwm,
+            percent: readings.percent,
+            curve_percent: self.curve_percent,
+            max_pwm: self.max_pwm,
+            group_boost: self.group_boost,
+            active_curve: self.active_curve.clone(),
+            target_rpm: self.target_rpm,
+            expected_rpm: self
+                .calibration
+                .as_ref()
+                .map(|calibration| calibration.expected_rpm(readings.pwm)),
+            stall_state: self.stall_state,
+        }
+    }
+
+    /// Kicks the fan again or gives up after `max_kicks`
+    fn kick_or_fail(&mut self, now: time::Instant) -> (StallState, StallEvent) {
+        if self.kicks >= self.stall.max_kicks {
+            return (StallState::Failed, StallEvent::Failed);
+        }
+        self.kicks += 1;
+        let until = now + Duration::from_secs_f64(self.stall.kick_duration);
+        (
+            StallState::Kicking(until),
+            StallEvent::Kick {
+                attempt: self.kicks,
+            },
+        )
+    }
+
+    /// Tracks whether the fan stopped although it is driven above its start pwm.
+    /// Returns the event of this update
+    fn check_stall(&mut self) -> Option<StallEvent> {
+        if !self.has_tach() || self.input_error {
+            return None;
+        }
+        let now = time::Instant::now();
+        let spinning = self.last_val > 0;
+        let driven = self.readings.lock().unwrap().pwm >= self.start_pwm;
+        let (state, event) = match self.stall_state {
+            StallState::Running if driven && !spinning => {
+                (StallState::Stalled(now), Some(StallEvent::Stalled))
+            }
+            StallState::Running => (StallState::Running, None),
+            StallState::Stalled(_) | StallState::Failed if spinning => {
+                (StallState::Running, Some(StallEvent::Recovered))
+            }
+            StallState::Stalled(_) if !driven => (StallState::Running, None),
+            StallState::Stalled(since)
+                if now - since >= Duration::from_secs_f64(self.stall.timeout) =>
+            {
+                let (state, event) = self.kick_or_fail(now);
+                (state, Some(event))
+            }
+            StallState::Kicking(until) if now >= until => match spinning {
+                true => (StallState::Running, Some(StallEvent::Recovered)),
+                false => {
+                    let (state, event) = self.kick_or_fail(now);
+                    (state, Some(event))
+                }
+            },
+            state => (state, None),
+        };
+        if spinning {
+            self.kicks = 0;
+        }
+        self.stall_state = state;
+        event
+    }
+
+    /// Switches the fan on or off unless it is held by the minimum on or off time.
+    /// Returns whether the fan is stopped
+    fn switch_power(&mut self, stop: bool, now: time::Instant) -> bool {
+        // Already in the requested state
+        if stop != self.powered {
+            return stop;
+        }
+        let hold = match self.powered {
+            true => self.start_stop.min_on_time,
+            false => self.start_stop.min_off_time,
+        };
+        if self
+            .last_switch
+            .is_some_and(|last_switch| now - last_switch < Duration::from_secs_f64(hold))
+        {
+            return !self.powered;
+        }
+        self.powered = !stop;
+        self.last_switch = Some(now);
+        if let Some(spin_up) = self
+            .start_stop
+            .spin_up
+            .filter(|_| !stop && !self.is_spinning())
+        {
+            debug!("Spinning up fan {} for {spin_up} s", self.id);
+            self.spin_up_until = Some(now + Duration::from_secs_f64(spin_up));
+        }
+        stop
+    }
+
+    /// Relative deviation of the measured rpm from the calibration at the current pwm
+    pub fn rpm_deviation(&self) -> Option<f64> {
+        if !self.has_tach() {
+            return None;
+        }
+        let expected = self
+            .calibration
+            .as_ref()?
+            .expected_rpm(self.readings.lock().unwrap().pwm);
+        if expected <= 0.0 {
+            return None;
+        }
+        Some((self.last_val as f64 - expected).abs() / expected)
     }
 
+    /// Maps the percentage to pwm using the pwm map if configured, else the rpm range of the
+    /// calibration if linearized. A `min_pwm` of 0 stops the fan at low percentages and always
+    /// uses the pwm range. The result never exceeds `max_pwm` unless `min_pwm` is higher
+    fn pwm_for_percentage(&self, percentage: f64, min_pwm: u8) -> u8 {
+        let max_pwm = self.max_pwm.max(min_pwm);
+        let pwm = match (&self.pwm_map, &self.calibration) {
+            (Some(map), _) => Self::map_percentage(map, percentage).max(min_pwm),
+            (None, Some(calibration)) if self.linearize && min_pwm != 0 => {
+                calibration.pwm_for_percentage(percentage).max(min_pwm)
+            }
+            _ => Self::percentage_to_pwm(percentage, min_pwm, max_pwm),
+        };
+        pwm.min(max_pwm)
+    }
+
+    /// Clamps the curves' output to the configured percentage range
+    fn clamp_percentage(&mut self, percentage: f64) -> f64 {
+        self.curve_percent = percentage;
+        percentage.clamp(self.min_percent, self.max_percent)
+    }
+
+    /// Combines the outputs of all curves and tracks the curve determining the result
+    fn combined_output(&mut self) -> f64 {
+        let values: Vec<(&String, f64)> = self
+            .curves
+            .iter()
+            .map(|(id, curve)| (id, curve.lock().unwrap().get_value().as_scaled_value()))
+            .collect();
+        let by_value = |a: &&(&String, f64), b: &&(&String, f64)| a.1.total_cmp(&b.1);
+        let (active, output) = match self.combine {
+            CombineRule::max => {
+                let (id, val) = values.iter().max_by(by_value).unwrap();
+                (Some(*id), *val)
+            }
+            CombineRule::min => {
+                let (id, val) = values.iter().min_by(by_value).unwrap();
+                (Some(*id), *val)
+            }
+            CombineRule::average => (
+                None,
+                values.iter().map(|(_id, val)| val).sum::<f64>() / values.len() as f64,
+            ),
+            CombineRule::priority => {
+                let (id, val) = values
+                    .iter()
+                    .find(|(_id, val)| *val > 0.0)
+                    .unwrap_or(values.last().unwrap());
+                (Some(*id), *val)
+            }
+        };
+        if values.len() > 1 && self.active_curve.as_ref() != active {
+            info!(
+                "Fan {} is now driven by curve {}",
+                self.id,
+                active.map_or("average", |id| id.as_str())
+            );
+        }
+        self.active_curve = active.cloned();
+        output
+    }
+
+    /// Percentage to apply. In rpm mode the curves' output is the target rpm
+    fn target_percentage(&mut self) -> f64 {
+        let output = self.combined_output();
+        let has_tach = self.has_tach();
+        let Some(controller) = self.rpm_controller.as_mut() else {
+            return output;
+        };
+        let now = time::Instant::now();
+        let dt = (now - controller.last_update).as_secs_f64();
+        controller.last_update = now;
+        let rpm = (has_tach && !self.input_error).then_some(self.last_val);
+        if rpm.is_none() && has_tach {
+            warn!("Tach of fan {} unavailable. Running open-loop", self.id);
+        }
+        self.target_rpm = Some(output);
+        controller.update(output, rpm, dt)
+    }
+
+    /// Writes the pwm and publishes it to the readings
+    fn set_pwm(&mut self, percentage: f64, pwm: u8) {
+        self.fan_pwm.set_output(pwm);
+        let mut readings = self.readings.lock().unwrap();
+        readings.pwm = pwm;
+        readings.percent = percentage;
+    }
+
+    /// Sets the pwm and waits until the rpm settles. Returns the mean of the settled window
     fn measure_pwm(
         &mut self,
         pwm: u8,
-        max_rpm_diff: i32,
-        wait_time: Duration,
-        stop_signal: Arc<AtomicBool>,
-    ) -> Option<i32> {
+        settle: &SettleCriteria,
+        stop_signal: &AtomicBool,
+    ) -> Result<i32, CalibrationError> {
         debug!("Measuring fan {} with pwm of {}", self.id, pwm);
         self.fan_pwm.set_output(pwm);
-        let mut max_diff = 10000;
+        let start = time::Instant::now();
         let mut rpms: VecDeque<i32> = VecDeque::new();
-        let mut mean = 0;
-        while max_diff > max_rpm_diff {
+        loop {
             if !stop_signal.load(Ordering::SeqCst) {
                 debug!("Stop signal received. Stopping measurement");
-                return None;
+                return Err(CalibrationError::Stopped);
             }
-            thread::sleep(wait_time);
+            if start.elapsed() > settle.timeout {
+                return Err(CalibrationError::Unsettled {
+                    pwm,
+                    rpms: rpms.into(),
+                });
+            }
+            thread::sleep(settle.interval);
             self.update_input();
             let rpm = self.get_value().as_scaled_value() as i32;
             rpms.push_front(rpm);
-            if rpms.len() > 5 {
+            if rpms.len() > settle.window {
                 rpms.pop_back();
-                mean = rpms.iter().sum::<i32>() / rpms.len() as i32;
-                max_diff = *rpms
-                    .iter()
-                    .max_by(|a, b| {
-                        let diff_a = i32::abs(mean - *a);
-                        let diff_b = i32::abs(mean - *b);
-
-                        diff_a.cmp(&diff_b)
-                    })
-                    .unwrap();
-                max_diff = i32::abs(max_diff - mean);
+            }
+            if rpms.len() == settle.window {
+                let mean = rpms.iter().sum::<i32>() / rpms.len() as i32;
+                let max_diff = rpms.iter().map(|rpm| (mean - rpm).abs()).max().unwrap();
                 debug!(
                     "Measured max diff of {} with vals {:?} and mean {mean} for fan {}",
                     max_diff, rpms, self.id
                 );
+                if max_diff <= settle.tolerance {
+                    return Ok(mean);
+                }
             }
         }
-        Some(mean)
     }
 
     pub fn measure_fan(
         &mut self,
-        wait_time: Duration,
-        max_rpm_diff: i32,
-        stop_signal: Arc<AtomicBool>,
-    ) -> Option<(u8, u8)> {
+        settle: &SettleCriteria,
+        stop_signal: &AtomicBool,
+    ) -> Result<FanMeasurement, CalibrationError> {
         debug!("Measuring fan {}", self.id);
+        if !self.has_tach() {
+            return Err(CalibrationError::NoTach);
+        }
         let mut max_pwm = 255u8;
         let mut min_pwm = 0u8;
         // stop fan to actually measure start pwm
-        let rpm = self
-            .measure_pwm(0, max_rpm_diff, wait_time, stop_signal.clone())
-            .unwrap();
+        let rpm = self.measure_pwm(0, settle, stop_signal)?;
         // Fan does not stop
         if rpm != 0 {
             max_pwm = 0;
@@ -186,89 +862,142 @@ impl FanSensor {
             let pwm = (max_pwm - min_pwm) / 2 + min_pwm;
             debug!("max_pwm {max_pwm} min_pwm {min_pwm}");
             debug!("##Setting fan {} to {}", self.id, pwm);
-            let rpm = self.measure_pwm(pwm, max_rpm_diff, wait_time, stop_signal.clone());
-            match rpm {
-                Some(rpm) => {
-                    debug!("Settled rpm {rpm} with pwm {pwm}");
-                    if rpm != 0 {
-                        max_pwm = pwm;
-                        debug!("Rpm is != 0. Found new lowest start pwm: {pwm}");
-                        self.start_pwm = pwm;
-                        // stop fan to actually measure start pwm
-                        self.measure_pwm(0, max_rpm_diff, wait_time, stop_signal.clone());
-                    } else {
-                        min_pwm = pwm;
-                    }
-                }
-                // SIGINT received
-                None => return None,
+            let rpm = self.measure_pwm(pwm, settle, stop_signal)?;
+            debug!("Settled rpm {rpm} with pwm {pwm}");
+            if rpm != 0 {
+                max_pwm = pwm;
+                debug!("Rpm is != 0. Found new lowest start pwm: {pwm}");
+                // stop fan to actually measure start pwm
+                self.measure_pwm(0, settle, stop_signal)?;
+            } else {
+                min_pwm = pwm;
             }
         }
         debug!("Found start pwm: {max_pwm}");
+        let start_pwm = max_pwm;
         // Set to 0 in case the fan never stops
-        self.min_pwm = 0;
+        let mut min_pwm = 0;
         debug!("Finding min_pwm for {}", self.id);
         // Measure min pwm
-        self.measure_pwm(255, max_rpm_diff, wait_time, stop_signal.clone())
-            .unwrap();
-        for pwm in (0..self.start_pwm + 3).rev() {
-            let rpm = self.measure_pwm(pwm, max_rpm_diff, wait_time, stop_signal.clone());
-            match rpm {
-                Some(rpm) => {
-                    debug!("Settled rpm {rpm} with pwm {pwm}");
-                    if rpm == 0 {
-                        break;
-                    }
-                    debug!("New min_pwm {pwm}");
-                    self.min_pwm = pwm;
-                }
-                None => return None,
+        self.measure_pwm(255, settle, stop_signal)?;
+        for pwm in (0..start_pwm.saturating_add(3)).rev() {
+            let rpm = self.measure_pwm(pwm, settle, stop_signal)?;
+            debug!("Settled rpm {rpm} with pwm {pwm}");
+            if rpm == 0 {
+                break;
             }
+            debug!("New min_pwm {pwm}");
+            min_pwm = pwm;
         }
         debug!(
-            "PWM measurement for {} is min {} start {}",
-            self.id, self.min_pwm, self.start_pwm
+            "PWM measurement for {} is min {min_pwm} start {start_pwm}",
+            self.id
         );
-        Some((self.min_pwm, self.start_pwm))
+
+        // Sweep down from full speed so the fan keeps spinning until min_pwm
+        let mut rpm_table = BTreeMap::new();
+        let mut pwm = 255u8;
+        loop {
+            let rpm = self.measure_pwm(pwm, settle, stop_signal)?;
+            debug!("Sweep of {} settled at {rpm} rpm with pwm {pwm}", self.id);
+            rpm_table.insert(pwm, rpm.max(0) as u32);
+            if pwm <= min_pwm {
+                break;
+            }
+            pwm = pwm.saturating_sub(SWEEP_STEP).max(min_pwm);
+        }
+        let calibration = FanCalibration {
+            max_rpm: rpm_table.values().copied().max().unwrap_or(0),
+            rpm: rpm_table,
+        };
+        // Only keep the results once the whole measurement succeeded
+        self.min_pwm = min_pwm;
+        self.start_pwm = start_pwm;
+        self.calibration = Some(calibration.clone());
+        Ok(FanMeasurement {
+            min_pwm,
+            start_pwm,
+            calibration,
+        })
     }
 }
 
 impl UpdatableOutput for FanSensor {
     fn update_output(&mut self) {
         self.update_input();
-        let percentage = self.curve.lock().unwrap().get_value().as_scaled_value();
-        println!("{}", percentage);
-        // TODO: implement start pwm
-        let mut min_pwm = if self.is_spinning() {
+        match self.check_stall() {
+            Some(StallEvent::Stalled) => warn!(
+                "Fan {} stopped at pwm {}",
+                self.id,
+                self.readings.lock().unwrap().pwm
+            ),
+            Some(StallEvent::Kick { attempt }) => warn!(
+                "Kicking stalled fan {} to full speed (attempt {attempt}/{})",
+                self.id, self.stall.max_kicks
+            ),
+            Some(StallEvent::Recovered) => info!("Fan {} is spinning again", self.id),
+            Some(StallEvent::Failed) => error!(
+                "Fan {} stalled and did not recover after {} kicks",
+                self.id, self.stall.max_kicks
+            ),
+            None => {}
+        }
+        if let StallState::Kicking(_) = self.stall_state {
+            self.set_pwm(100.0, 255);
+            return;
+        }
+        let percentage = self.target_percentage() + self.group_boost;
+        let percentage = self.clamp_percentage(percentage);
+        // A stopped fan needs the start pwm to spin up
+        let min_pwm = if self.is_spinning() {
             self.min_pwm
         } else {
             self.start_pwm
         };
 
-        // add 3 for safety
-        //min_pwm = min_pwm + 3;
-
-        if percentage < self.start_percent && self.get_value().as_scaled_value() == 0.0 {
+        let now = time::Instant::now();
+        let mut stop = false;
+        if percentage < self.start_stop.start_percent && !self.is_spinning() && !self.group_running
+        {
             // set to 0 if the fan is not spinning and we are below start_percent
-            min_pwm = 0;
+            stop = true;
         }
 
-        if percentage < 10.0 {
-            // fan is currenlty on. We don't use is spnning as we might be faster than the motor
+        if percentage < self.start_stop.stop_percent {
+            // fan is currently on. We don't use is spinning as we might be faster than the motor
             match self.zero_percent_time {
                 Some(time) => {
-                    if time::Instant::now() - time > time::Duration::from_secs(10) {
-                        min_pwm = 0;
+                    if now - time > Duration::from_secs_f64(self.start_stop.stop_delay) {
+                        stop = true;
                     }
                 }
-                None => self.zero_percent_time = Some(time::Instant::now()),
+                None => self.zero_percent_time = Some(now),
             }
         } else {
             self.zero_percent_time = None;
         }
-        let pwm_range = 255 - min_pwm;
-        let pwm_val = (percentage / 100.0).mul_add(pwm_range as f64, min_pwm as f64);
-        self.fan_pwm.set_output(pwm_val as u8);
+        let stop = self.start_stop.zero_rpm && stop;
+        let pwm_val = match self.switch_power(stop, now) {
+            // Held off by the minimum off time
+            true if !stop => 0,
+            true => self.pwm_for_percentage(percentage, 0),
+            false if self.spin_up_until.is_some_and(|until| now < until) => 255,
+            false => self.pwm_for_percentage(percentage, min_pwm),
+        };
+        // Only compare against the calibration once the fan settled at the pwm
+        if self.readings.lock().unwrap().pwm == pwm_val {
+            let unhealthy = self
+                .rpm_deviation()
+                .is_some_and(|deviation| deviation > HEALTH_TOLERANCE);
+            if unhealthy && !self.unhealthy {
+                warn!(
+                    "Fan {} runs at {} rpm which deviates from the calibration at pwm {pwm_val}",
+                    self.id, self.last_val
+                );
+            }
+            self.unhealthy = unhealthy;
+        }
+        self.set_pwm(percentage, pwm_val);
         trace!(
             "Got value {percentage} for fan {} pwm {pwm_val} min pwm {min_pwm}",
             self.id,
@@ -276,12 +1005,29 @@ impl UpdatableOutput for FanSensor {
     }
 }
 
+// Also runs when unwinding from a panic
+impl Drop for FanSensor {
+    fn drop(&mut self) {
+        self.release();
+    }
+}
+
 impl UpdatableInput for FanSensor {
     fn update_input(&mut self) {
-        let val: Result<AngularVelocity, Box<dyn Error>> = self.fan_input.get_input();
+        let Some(fan_input) = &self.fan_input else {
+            return;
+        };
+        let val: Result<AngularVelocity, Box<dyn Error>> = fan_input.get_input();
         match val {
-            Ok(speed) => self.last_val = speed.as_rpm(),
-            Err(err) => error!("Failed to read sensor {} with error {}", self.id, err),
+            Ok(speed) => {
+                self.last_val = speed.as_rpm();
+                self.input_error = false;
+                self.readings.lock().unwrap().rpm = self.last_val;
+            }
+            Err(err) => {
+                self.input_error = true;
+                error!("Failed to read sensor {} with error {}", self.id, err)
+            }
         }
     }
 }
@@ -294,12 +1040,12 @@ impl ReadableValue for FanSensor {
 
 #[cfg(test)]
 mod test {
-    use std::time::Instant;
+    use std::{sync::atomic::AtomicUsize, time::Instant};
 
-    use more_asserts::{assert_ge, assert_le};
+    use more_asserts::{assert_ge, assert_le, assert_lt};
 
     use crate::{
-        config::{FileConfig, SensorType},
+        config::{FanCurves, FileConfig, SensorType},
         curve::StaticCurve,
     };
 
@@ -334,18 +1080,100 @@ mod test {
             id: "test_sensor".to_string(),
             minpwm: Some(21),
             startpwm: Some(42),
-            curve: "dummy".to_string(),
+            maxpwm: None,
+            min_percent: None,
+            max_percent: None,
+            pwm_map: None,
+            calibration: None,
+            linearize: false,
+            curve: FanCurves::Single("dummy".into()),
+            combine: CombineRule::max,
+            mode: FanMode::percent,
+            rpm_control: None,
+            shutdown: Default::default(),
+            stall: Default::default(),
+            start_stop: Default::default(),
             sensor,
         };
         let fan = FanSensor::new(
             &fan_config,
-            fan_input,
+            Some(fan_input),
             Box::new(DummyPwm { last_val: 0 }),
-            static_sensor.clone(),
+            vec![("dummy".to_string(), static_sensor.clone())],
+            Arc::new(Mutex::new(FanReadings::default())),
         );
         (fan, fan_input_val_2, static_sensor)
     }
 
+    fn test_settle() -> SettleCriteria {
+        SettleCriteria {
+            interval: Duration::from_nanos(0),
+            window: 5,
+            tolerance: 5,
+            timeout: Duration::from_secs(10),
+        }
+    }
+
+    #[test]
+    fn test_measure_unsettled() {
+        let (mut fan, _fan_input_val, _static_sensor) = init();
+        // Tach alternating between two readings never settles
+        let mut fan_input = Box::new(MockFanInput::new());
+        let toggle = AtomicBool::new(false);
+        fan_input.expect_get_input().returning(move || {
+            let high = toggle.fetch_xor(true, Ordering::SeqCst);
+            Ok(AngularVelocity::from_rpm(if high { 2000u32 } else { 1000 }))
+        });
+        fan.fan_input = Some(fan_input);
+        let settle = SettleCriteria {
+            timeout: Duration::from_millis(10),
+            ..test_settle()
+        };
+        let err = fan
+            .measure_fan(&settle, &AtomicBool::new(true))
+            .unwrap_err();
+        assert!(
+            matches!(err, CalibrationError::Unsettled { pwm: 0, .. }),
+            "{err}"
+        );
+
+        assert_eq!(
+            fan.measure_fan(&settle, &AtomicBool::new(false)),
+            Err(CalibrationError::Stopped)
+        );
+    }
+
+    #[test]
+    fn test_measure_keeps_limits() {
+        let (mut fan, _fan_input_val, _static_sensor) = init();
+        // Tach settles at pwm 0, so the fan never stops, then fails to settle at full speed
+        let mut fan_input = Box::new(MockFanInput::new());
+        let reads = AtomicUsize::new(0);
+        fan_input.expect_get_input().returning(move || {
+            let read = reads.fetch_add(1, Ordering::SeqCst);
+            let rpm = match read < 5 || read.is_multiple_of(2) {
+                true => 1000u32,
+                false => 2000,
+            };
+            Ok(AngularVelocity::from_rpm(rpm))
+        });
+        fan.fan_input = Some(fan_input);
+        let settle = SettleCriteria {
+            timeout: Duration::from_millis(10),
+            ..test_settle()
+        };
+        let err = fan
+            .measure_fan(&settle, &AtomicBool::new(true))
+            .unwrap_err();
+        assert!(
+            matches!(err, CalibrationError::Unsettled { pwm: 255, .. }),
+            "{err}"
+        );
+        assert_eq!(fan.min_pwm, 21);
+        assert_eq!(fan.start_pwm, 42);
+        assert!(fan.calibration.is_none());
+    }
+
     #[test]
     fn test_measure() {
         measure_fan(21, 42);
@@ -398,16 +1226,44 @@ mod test {
             id: "test_sensor".to_string(),
             minpwm: None,
             startpwm: None,
-            curve: "dummy".to_string(),
+            maxpwm: None,
+            min_percent: None,
+            max_percent: None,
+            pwm_map: None,
+            calibration: None,
+            linearize: false,
+            curve: FanCurves::Single("dummy".into()),
+            combine: CombineRule::max,
+            mode: FanMode::percent,
+            rpm_control: None,
+            shutdown: Default::default(),
+            stall: Default::default(),
+            start_stop: Default::default(),
             sensor,
         };
-        let mut fan = FanSensor::new(&fan_config, fan_input, fan_output, static_sensor.clone());
+        let mut fan = FanSensor::new(
+            &fan_config,
+            Some(fan_input),
+            fan_output,
+            vec![("dummy".to_string(), static_sensor.clone())],
+            Arc::new(Mutex::new(FanReadings::default())),
+        );
         let range = fan
-            .measure_fan(Duration::from_nanos(0), 5, Arc::new(AtomicBool::new(true)))
+            .measure_fan(&test_settle(), &AtomicBool::new(true))
             .unwrap();
 
-        assert_eq!(range.0, min_pwm);
-        assert_eq!(range.1, start_pwm);
+        assert_eq!(range.min_pwm, min_pwm);
+        assert_eq!(range.start_pwm, start_pwm);
+        // Simulated fan runs at (pwm + 1) * 100 rpm
+        let calibration = range.calibration;
+        assert_eq!(calibration.max_rpm, 25600);
+        assert_eq!(calibration.rpm[&255], 25600);
+        assert_eq!(calibration.rpm[&239], 24000);
+        assert_eq!(
+            calibration.rpm.keys().next(),
+            Some(&min_pwm),
+            "Sweep ends at min pwm"
+        );
     }
 
     #[test]
@@ -456,4 +1312,377 @@ mod test {
         fan.update_output();
         assert_eq!(fan.fan_pwm.get_output(), 0);
     }
+
+    #[test]
+    fn test_shutdown() {
+        // pwm_enable and pwm of the simulated output
+        let state = Arc::new(Mutex::new((5u8, 100u8)));
+        let sensor = |shutdown| {
+            let (mut fan, _fan_input_val, _static_sensor) = init();
+            let mut fan_output = MockFanOutput::new();
+            let output_state = state.clone();
+            fan_output
+                .expect_set_output()
+                .returning(move |pwm| output_state.lock().unwrap().1 = pwm);
+            let output_state = state.clone();
+            fan_output
+                .expect_get_output()
+                .returning(move || output_state.lock().unwrap().1);
+            let output_state = state.clone();
+            fan_output
+                .expect_set_enable()
+                .returning(move |enable| output_state.lock().unwrap().0 = enable);
+            let output_state = state.clone();
+            fan_output
+                .expect_get_enable()
+                .returning(move || Some(output_state.lock().unwrap().0));
+            fan.fan_pwm = Box::new(fan_output);
+            fan.shutdown = shutdown;
+            fan
+        };
+
+        let mut fan = sensor(ShutdownState::restore);
+        fan.take_control();
+        assert_eq!(*state.lock().unwrap(), (PWM_ENABLE_MANUAL, 100));
+        fan.set_percentage(100.0);
+        assert_eq!(*state.lock().unwrap(), (PWM_ENABLE_MANUAL, 255));
+        drop(fan);
+        assert_eq!(*state.lock().unwrap(), (5, 100));
+
+        let mut fan = sensor(ShutdownState::full_speed);
+        fan.take_control();
+        fan.set_percentage(0.0);
+        fan.release();
+        assert_eq!(*state.lock().unwrap(), (PWM_ENABLE_MANUAL, 255));
+        drop(fan);
+
+        *state.lock().unwrap() = (PWM_ENABLE_MANUAL, 100);
+        let mut fan = sensor(ShutdownState::auto);
+        fan.take_control();
+        drop(fan);
+        assert_eq!(state.lock().unwrap().0, PWM_ENABLE_AUTO);
+
+        let mut fan = sensor(ShutdownState::last);
+        fan.take_control();
+        fan.set_percentage(100.0);
+        drop(fan);
+        assert_eq!(*state.lock().unwrap(), (PWM_ENABLE_MANUAL, 255));
+    }
+
+    #[test]
+    fn test_stall() {
+        let (mut fan, fan_input_val, static_sensor) = init();
+        let pwm = Arc::new(Mutex::new(0u8));
+        let mut fan_output = MockFanOutput::new();
+        let set_pwm = pwm.clone();
+        fan_output
+            .expect_set_output()
+            .returning(move |val| *set_pwm.lock().unwrap() = val);
+        let get_pwm = pwm.clone();
+        fan_output
+            .expect_get_output()
+            .returning(move || *get_pwm.lock().unwrap());
+        fan.fan_pwm = Box::new(fan_output);
+        fan.stall = StallConfig {
+            timeout: 0.0,
+            kick_duration: 0.0,
+            max_kicks: 2,
+        };
+
+        *fan_input_val.lock().unwrap() = 1000;
+        static_sensor.lock().unwrap().value = 50;
+        fan.update_output();
+        fan.update_output();
+        assert_eq!(fan.stall_state, StallState::Running);
+
+        // Tach stops although the fan is driven above its start pwm
+        *fan_input_val.lock().unwrap() = 0;
+        fan.update_output();
+        assert!(matches!(fan.stall_state, StallState::Stalled(_)));
+        fan.update_output();
+        assert!(matches!(fan.stall_state, StallState::Kicking(_)));
+        assert_eq!(*pwm.lock().unwrap(), 255);
+        fan.update_input();
+        assert_eq!(fan.check_stall(), Some(StallEvent::Kick { attempt: 2 }));
+        fan.update_input();
+        assert_eq!(fan.check_stall(), Some(StallEvent::Failed));
+        fan.update_output();
+        assert_eq!(fan.stall_state, StallState::Failed);
+        assert_eq!(fan.status().stall_state, StallState::Failed);
+        assert_ne!(*pwm.lock().unwrap(), 255);
+
+        // Recovers once the tach reports rpm again
+        *fan_input_val.lock().unwrap() = 800;
+        fan.update_output();
+        assert_eq!(fan.stall_state, StallState::Running);
+
+        // A kick that starts the fan recovers it
+        *fan_input_val.lock().unwrap() = 0;
+        fan.update_output();
+        fan.update_output();
+        assert!(matches!(fan.stall_state, StallState::Kicking(_)));
+        *fan_input_val.lock().unwrap() = 900;
+        fan.update_input();
+        assert_eq!(fan.check_stall(), Some(StallEvent::Recovered));
+        assert_eq!(fan.kicks, 0);
+
+        // Not a stall if the fan is driven below its start pwm
+        *fan_input_val.lock().unwrap() = 0;
+        static_sensor.lock().unwrap().value = 0;
+        fan.zero_percent_time = Some(Instant::now() - Duration::from_secs(100));
+        fan.update_output();
+        fan.update_output();
+        assert_eq!(*pwm.lock().unwrap(), 0);
+        assert_eq!(fan.stall_state, StallState::Running);
+    }
+
+    #[test]
+    fn test_start_stop() {
+        let (mut fan, fan_input_val, static_sensor) = init();
+        fan.start_stop.stop_delay = 0.0;
+        fan.start_stop.min_on_time = 100.0;
+        fan.start_stop.spin_up = Some(100.0);
+
+        // Spins up at full speed from standstill
+        static_sensor.lock().unwrap().value = 50;
+        fan.update_output();
+        assert_eq!(fan.fan_pwm.get_output(), 255);
+        *fan_input_val.lock().unwrap() = 1000;
+        fan.update_output();
+        assert_eq!(fan.fan_pwm.get_output(), 255);
+        fan.spin_up_until = None;
+        fan.update_output();
+        assert_lt!(fan.fan_pwm.get_output(), 255);
+
+        // Keeps running for the minimum on time
+        static_sensor.lock().unwrap().value = 0;
+        fan.update_output();
+        fan.update_output();
+        assert_eq!(fan.fan_pwm.get_output(), 21);
+        fan.last_switch = Some(Instant::now() - Duration::from_secs(1000));
+        fan.update_output();
+        assert_eq!(fan.fan_pwm.get_output(), 0);
+
+        // Stays off for the minimum off time
+        *fan_input_val.lock().unwrap() = 0;
+        fan.start_stop.min_off_time = 100.0;
+        static_sensor.lock().unwrap().value = 50;
+        fan.update_output();
+        assert_eq!(fan.fan_pwm.get_output(), 0);
+        fan.last_switch = Some(Instant::now() - Duration::from_secs(1000));
+        fan.update_output();
+        assert_eq!(fan.fan_pwm.get_output(), 255);
+    }
+
+    #[test]
+    fn test_zero_rpm_disabled() {
+        let (mut fan, _fan_input_val, static_sensor) = init();
+        fan.start_stop.zero_rpm = false;
+        fan.update_output();
+        assert_eq!(fan.fan_pwm.get_output(), 42);
+        fan.zero_percent_time = Some(Instant::now() - Duration::from_secs(100));
+        fan.update_output();
+        assert_ge!(fan.fan_pwm.get_output(), 21);
+        static_sensor.lock().unwrap().value = 100;
+        fan.update_output();
+        assert_eq!(fan.fan_pwm.get_output(), 255);
+    }
+
+    #[test]
+    fn test_output_limits() {
+        let (mut fan, fan_input_val, static_sensor) = init();
+        *fan_input_val.lock().unwrap() = 1000;
+        fan.max_pwm = 200;
+        static_sensor.lock().unwrap().value = 100;
+        fan.update_output();
+        assert_eq!(fan.fan_pwm.get_output(), 200);
+
+        fan.min_percent = 30.0;
+        fan.max_percent = 80.0;
+        fan.update_output();
+        assert_eq!(fan.fan_pwm.get_output(), 164);
+        assert_eq!(
+            fan.status().to_string(),
+            "test_sensor: 1000 rpm, pwm 164/200, 80.0% (clamped from 100.0%) from dummy"
+        );
+        static_sensor.lock().unwrap().value = 0;
+        fan.update_output();
+        assert_eq!(fan.readings.lock().unwrap().percent, 30.0);
+
+        // Map overrides the linear pwm range but respects min and max pwm
+        fan.pwm_map = Some(BTreeMap::from([(0, 0), (50, 60), (100, 255)]));
+        fan.update_output();
+        assert_eq!(fan.fan_pwm.get_output(), 36);
+        static_sensor.lock().unwrap().value = 75;
+        fan.update_output();
+        assert_eq!(fan.fan_pwm.get_output(), 157);
+        fan.max_percent = 100.0;
+        static_sensor.lock().unwrap().value = 100;
+        fan.update_output();
+        assert_eq!(fan.fan_pwm.get_output(), 200);
+    }
+
+    #[test]
+    fn test_group_start() {
+        let (mut fan, _fan_input_val, static_sensor) = init();
+        static_sensor.lock().unwrap().value = 10;
+        fan.update_output();
+        assert_lt!(fan.fan_pwm.get_output(), 42);
+        assert!(!fan.is_powered());
+        // Starts with its own start pwm once another member of its group runs
+        fan.group_running = true;
+        fan.update_output();
+        assert_ge!(fan.fan_pwm.get_output(), 42);
+        assert!(fan.is_powered());
+    }
+
+    #[test]
+    fn test_fan_without_tach() {
+        let (mut fan, _fan_input_val, static_sensor) = init();
+        fan.fan_input = None;
+        fan.update_output();
+        assert_eq!(fan.fan_pwm.get_output(), 0);
+        assert!(!fan.is_spinning());
+        // Starts with the start pwm and keeps running down to the min pwm
+        static_sensor.lock().unwrap().value = 30;
+        fan.update_output();
+        assert_ge!(fan.fan_pwm.get_output(), 42);
+        assert!(fan.is_spinning());
+        static_sensor.lock().unwrap().value = 1;
+        fan.zero_percent_time = None;
+        fan.update_output();
+        assert_ge!(fan.fan_pwm.get_output(), 21);
+        assert_le!(fan.fan_pwm.get_output(), 42);
+
+        assert_eq!(fan.status().rpm, None);
+        assert_eq!(fan.rpm_deviation(), None);
+        assert_eq!(
+            fan.measure_fan(&test_settle(), &AtomicBool::new(true)),
+            Err(CalibrationError::NoTach)
+        );
+    }
+
+    #[test]
+    fn test_fan_readings() {
+        let (mut fan, fan_input_val, static_sensor) = init();
+        let reading = |reading| FanReadingValue {
+            readings: fan.readings.clone(),
+            reading,
+        };
+        let (rpm, pwm, percent) = (
+            reading(FanReading::rpm),
+            reading(FanReading::pwm),
+            reading(FanReading::percent),
+        );
+
+        *fan_input_val.lock().unwrap() = 1200;
+        static_sensor.lock().unwrap().value = 100;
+        fan.update_output();
+        assert_eq!(
+            rpm.get_value().get_sensor_type(),
+            crate::common::SensorType::RPM
+        );
+        assert_eq!(rpm.get_value().as_scaled_value(), 1200.0);
+        assert_eq!(pwm.get_value().as_raw_value(), 255.0);
+        assert_eq!(pwm.get_value().as_scaled_value(), 100.0);
+        assert_eq!(percent.get_value().as_scaled_value(), 100.0);
+
+        static_sensor.lock().unwrap().value = 50;
+        fan.update_output();
+        assert_eq!(percent.get_value().as_scaled_value(), 50.0);
+        assert_eq!(
+            pwm.get_value().as_raw_value(),
+            fan.fan_pwm.get_output() as f64
+        );
+    }
+
+    #[test]
+    fn test_combine() {
+        let (mut fan, _fan_input_val, static_sensor) = init();
+        let second = Arc::new(Mutex::new(StaticCurve { value: 60 }));
+        fan.curves.push(("second".to_string(), second.clone()));
+        static_sensor.lock().unwrap().value = 20;
+
+        assert_eq!(fan.combined_output(), 60.0);
+        assert_eq!(fan.active_curve.as_deref(), Some("second"));
+        fan.combine = CombineRule::min;
+        assert_eq!(fan.combined_output(), 20.0);
+        assert_eq!(fan.active_curve.as_deref(), Some("dummy"));
+        fan.combine = CombineRule::average;
+        assert_eq!(fan.combined_output(), 40.0);
+        assert_eq!(fan.active_curve, None);
+
+        // Priority falls through curves at 0%
+        fan.combine = CombineRule::priority;
+        assert_eq!(fan.combined_output(), 20.0);
+        assert_eq!(fan.active_curve.as_deref(), Some("dummy"));
+        static_sensor.lock().unwrap().value = 0;
+        assert_eq!(fan.combined_output(), 60.0);
+        assert_eq!(fan.active_curve.as_deref(), Some("second"));
+        second.lock().unwrap().value = 0;
+        assert_eq!(fan.combined_output(), 0.0);
+
+        fan.update_output();
+        assert_eq!(
+            fan.status().to_string(),
+            "test_sensor: 0 rpm, pwm 0, 0.0% from second"
+        );
+    }
+
+    #[test]
+    fn test_rpm_controller() {
+        let mut controller = RpmController::new(&RpmControl {
+            max_rpm: Some(2000),
+            ..Default::default()
+        });
+        // Simulated fan running at 20 rpm per percent
+        let mut rpm = 0;
+        let mut output = 0.0;
+        for _ in 0..200 {
+            let new_output = controller.update(1000.0, Some(rpm), 1.0);
+            // Correction speed is limited
+            assert_le!((new_output - output).abs(), 10.0 + 1e-9);
+            output = new_output;
+            rpm = (output * 20.0) as u32;
+        }
+        assert!((output - 50.0).abs() < 1.0, "{output}");
+
+        // Open-loop without a tach
+        assert_eq!(controller.update(1500.0, None, 1.0), 75.0);
+        let mut controller = RpmController::new(&RpmControl::default());
+        assert_eq!(controller.update(1500.0, None, 1.0), 100.0);
+    }
+
+    #[test]
+    fn test_calibration() {
+        let calibration = FanCalibration {
+            max_rpm: 2000,
+            rpm: BTreeMap::from([(50, 500), (150, 1500), (255, 2000)]),
+        };
+        assert_eq!(calibration.expected_rpm(100), 1000.0);
+        assert_eq!(calibration.expected_rpm(20), 500.0);
+        assert_eq!(calibration.expected_rpm(255), 2000.0);
+        assert_eq!(calibration.pwm_for_rpm(1000.0), 100);
+        assert_eq!(calibration.pwm_for_rpm(100.0), 50);
+        assert_eq!(calibration.pwm_for_rpm(3000.0), 255);
+        // 50% of 500..2000 rpm is 1250 rpm
+        assert_eq!(calibration.pwm_for_percentage(50.0), 125);
+
+        let (mut fan, fan_input_val, static_sensor) = init();
+        fan.calibration = Some(calibration);
+        fan.linearize = true;
+        *fan_input_val.lock().unwrap() = 1250;
+        static_sensor.lock().unwrap().value = 50;
+        fan.update_output();
+        assert_eq!(fan.fan_pwm.get_output(), 125);
+        fan.update_output();
+        assert_eq!(fan.rpm_deviation(), Some(0.0));
+        assert!(!fan.unhealthy);
+
+        // Clogged fan running way below the calibration
+        *fan_input_val.lock().unwrap() = 400;
+        fan.update_output();
+        assert!(fan.unhealthy);
+        assert_eq!(fan.status().expected_rpm, Some(1250.0));
+    }
 }
@@ -0,0 +1,143 @@
+use log::{info, trace, warn};
+
+use crate::{config::FailureCompensation, fan::FanContainer};
+
+/// Fans driven by the same curve
+pub struct FanGroup {
+    pub id: String,
+    pub members: Vec<FanContainer>,
+    pub on_failure: FailureCompensation,
+    /// Ids of the members that failed in the last update
+    pub failed: Vec<String>,
+}
+
+impl FanGroup {
+    /// Lets every member start below its start percentage once one member runs,
+    /// so the group starts as a unit
+    pub fn sync_start(&self) {
+        let running = self
+            .members
+            .iter()
+            .any(|fan| fan.lock().unwrap().is_powered());
+        trace!("Fan group {} running: {running}", self.id);
+        for fan in &self.members {
+            fan.lock().unwrap().group_running = running;
+        }
+    }
+
+    /// Raises the output of the remaining members while a member has failed
+    pub fn compensate(&mut self) {
+        let failed: Vec<String> = self
+            .members
+            .iter()
+            .map(|fan| fan.lock().unwrap())
+            .filter(|fan| fan.has_failed())
+            .map(|fan| fan.id.clone())
+            .collect();
+        let boost = match (failed.is_empty(), self.on_failure) {
+            (true, _) | (false, FailureCompensation::none) => 0.0,
+            (false, FailureCompensation::boost { percent }) => percent,
+            (false, FailureCompensation::full_speed) => 100.0,
+        };
+        if failed != self.failed {
+            match failed.is_empty() {
+                true => info!("All fans of group {} are running again", self.id),
+                false => warn!(
+                    "Fans {} of group {} failed. Compensating with {:?}",
+                    failed.join(", "),
+                    self.id,
+                    self.on_failure
+                ),
+            }
+        }
+        for fan in &self.members {
+            let mut fan = fan.lock().unwrap();
+            fan.group_boost = match failed.contains(&fan.id) {
+                true => 0.0,
+                false => boost,
+            };
+        }
+        self.failed = failed;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use libmedium::units::AngularVelocity;
+
+    use crate::{
+        common::UpdatableOutput,
+        config::FanConfig,
+        curve::StaticCurve,
+        fan::{FanReadings, FanSensor, MockFanInput, MockFanOutput, StallState},
+    };
+
+    use super::*;
+
+    fn member(id: &str) -> FanContainer {
+        let conf: FanConfig = serde_yaml::from_str(&format!(
+            "
+id: {id}
+minpwm: 20
+startpwm: 40
+sensor:
+  type: file
+  path: test
+"
+        ))
+        .unwrap();
+        let mut fan_input = MockFanInput::new();
+        fan_input
+            .expect_get_input()
+            .returning(|| Ok(AngularVelocity::from_rpm(1000u32)));
+        let mut fan_output = MockFanOutput::new();
+        fan_output.expect_set_output().return_const(());
+        let curve = Arc::new(Mutex::new(StaticCurve { value: 50 }));
+        Arc::new(Mutex::new(FanSensor::new(
+            &conf,
+            Some(Box::new(fan_input)),
+            Box::new(fan_output),
+            vec![("curve".to_string(), curve)],
+            Arc::new(Mutex::new(FanReadings::default())),
+        )))
+    }
+
+    #[test]
+    fn test_compensate() {
+        let (push, pull) = (member("push"), member("pull"));
+        let mut group = FanGroup {
+            id: "radiator".to_string(),
+            members: vec![push.clone(), pull.clone()],
+            on_failure: FailureCompensation::boost { percent: 20.0 },
+            failed: vec![],
+        };
+        group.compensate();
+        push.lock().unwrap().update_output();
+        assert_eq!(push.lock().unwrap().status().percent, 50.0);
+
+        pull.lock().unwrap().stall_state = StallState::Failed;
+        group.compensate();
+        assert_eq!(group.failed, ["pull"]);
+        push.lock().unwrap().update_output();
+        let status = push.lock().unwrap().status();
+        assert_eq!(status.percent, 70.0);
+        assert!(status
+            .to_string()
+            .ends_with("boosted by 20% for failed group members"));
+        assert_eq!(pull.lock().unwrap().group_boost, 0.0);
+
+        group.on_failure = FailureCompensation::full_speed;
+        group.compensate();
+        push.lock().unwrap().update_output();
+        assert_eq!(push.lock().unwrap().status().percent, 100.0);
+
+        // Compensation ends once the member runs again
+        pull.lock().unwrap().stall_state = StallState::Running;
+        group.compensate();
+        assert!(group.failed.is_empty());
+        push.lock().unwrap().update_output();
+        assert_eq!(push.lock().unwrap().status().percent, 50.0);
+    }
+}
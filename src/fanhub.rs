@@ -1,55 +1,102 @@
-use std::io::Write;
 use std::{
     collections::HashMap,
-    sync::{atomic::AtomicBool, Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
     time::Duration,
 };
 
 use libmedium::{hwmon::sync_hwmon::Hwmons, parse_hwmons};
-use log::{debug, error, info, warn};
+use log::{error, info};
+use rayon::prelude::*;
 
 use crate::{
-    common::{ReadableValueContainer, UpdatableInput, UpdatableOutput},
+    autotune::{self, AutotuneConfig, Gains},
+    common::{ReadableValue, ReadableValueContainer, SensorType, UpdatableInput, UpdatableOutput},
     config::{self, RufacoConfig},
     curve::{self, CurveContainer},
-    fan::{FanContainer, FanSensor},
+    fan::{
+        CalibrationError, FanContainer, FanMeasurement, FanReadingValue, FanReadings,
+        FanReadingsContainer, FanSensor, FanStatus, SettleCriteria,
+    },
+    fangroup::FanGroup,
     hwmon,
     temperature::{TempSensor, TempSensorContainer},
 };
 
+/// Fans with their ids
+type ChipFans = Vec<(String, FanContainer)>;
+
 pub struct FanHub {
     config: RufacoConfig,
     sensors: HashMap<String, TempSensorContainer>,
     curves: HashMap<String, CurveContainer>,
+    /// Curve ids ordered so every curve is updated after its dependencies
+    curve_order: Vec<String>,
     fans: HashMap<String, FanContainer>,
+    fan_groups: Vec<FanGroup>,
+}
+
+/// Drives a fan and observes a sensor for the autotune experiments
+struct AutotuneProcess {
+    fan: FanContainer,
+    sensor: TempSensorContainer,
+    interval: Duration,
+}
+
+impl autotune::Process for AutotuneProcess {
+    fn set_output(&mut self, percentage: f64) {
+        self.fan.lock().unwrap().set_percentage(percentage);
+    }
+
+    fn read(&mut self) -> f64 {
+        let mut sensor = self.sensor.lock().unwrap();
+        sensor.update_input();
+        sensor.get_value().as_scaled_value()
+    }
+
+    fn wait(&mut self) {
+        thread::sleep(self.interval);
+    }
 }
 
 fn get_sensor(
     sensor_id: &str,
     sensors: &HashMap<String, TempSensorContainer>,
     curves: &HashMap<String, CurveContainer>,
+    fan_readings: &HashMap<String, FanReadingsContainer>,
 ) -> ReadableValueContainer {
     let sensor: ReadableValueContainer;
+    let (fan_id, reading) = config::FanReading::parse(sensor_id);
     if sensors.contains_key(sensor_id) {
         sensor = sensors[sensor_id].clone();
     } else if curves.contains_key(sensor_id) {
         sensor = curves[sensor_id].clone();
+    } else if let Some(readings) = fan_readings.get(fan_id) {
+        sensor = Arc::new(Mutex::new(FanReadingValue {
+            readings: readings.clone(),
+            reading,
+        }));
     } else {
-        // FIXME: Configs are sensitive to the order.
-        todo!(
-            "Config doesn't contain {}! Be sure to place them in the correct order",
-            sensor_id
-        )
+        // Curves are loaded after their dependencies and the config is validated
+        unreachable!("Config doesn't contain {}!", sensor_id)
     }
     sensor
 }
 
 impl FanHub {
+    /// Loads all sensors. Sensor groups are returned as the ids of their members
     fn load_sensors(
         config: &RufacoConfig,
         hwmons: &Hwmons,
-    ) -> HashMap<String, TempSensorContainer> {
+    ) -> (
+        HashMap<String, TempSensorContainer>,
+        HashMap<String, Vec<String>>,
+    ) {
         let mut sensors: HashMap<String, TempSensorContainer> = HashMap::new();
+        let mut groups: HashMap<String, Vec<String>> = HashMap::new();
         for sensorconf in &config.sensors {
             match &sensorconf.sensor {
                 config::SensorType::hwmon(conf) => {
@@ -60,25 +107,69 @@ impl FanHub {
                     let id = rufaco_sensor.lock().unwrap().id.clone();
                     sensors.insert(id, rufaco_sensor);
                 }
+                config::SensorType::glob(conf) => {
+                    let members = hwmon::load_hwmon_sensor_group(hwmons, &conf.chip, &conf.label);
+                    if members.is_empty() {
+                        panic!(
+                            "Sensor group {} matched no sensors with chip {} and label {}",
+                            sensorconf.id, conf.chip, conf.label
+                        );
+                    }
+                    let member_ids = members
+                        .into_iter()
+                        .map(|(name, temp_sensor)| {
+                            let id = format!("{}[{name}]", sensorconf.id);
+                            let member = TempSensor {
+                                id: id.clone(),
+                                sensor: temp_sensor,
+                                last_val: 0,
+                            };
+                            sensors.insert(id.clone(), Arc::new(Mutex::new(member)));
+                            id
+                        })
+                        .collect();
+                    groups.insert(sensorconf.id.clone(), member_ids);
+                }
                 config::SensorType::file(_path) => todo!(),
             }
         }
-        sensors
+        (sensors, groups)
+    }
+
+    /// Creates the readings of every fan up front so curves can use them as input
+    fn load_fan_readings(config: &RufacoConfig) -> HashMap<String, FanReadingsContainer> {
+        config
+            .fans
+            .iter()
+            .map(|fan| (fan.id.clone(), Arc::new(Mutex::new(FanReadings::default()))))
+            .collect()
     }
 
     fn load_curves(
         config: &RufacoConfig,
         sensors: &HashMap<String, TempSensorContainer>,
+        groups: &HashMap<String, Vec<String>>,
+        fan_readings: &HashMap<String, FanReadingsContainer>,
     ) -> HashMap<String, ReadableValueContainer> {
+        // Groups are expanded into their members
+        let expand = |sensor_ids: &[config::CurveRef]| -> Vec<String> {
+            sensor_ids
+                .iter()
+                .flat_map(|sensor| match groups.get(sensor.id()) {
+                    Some(members) => members.clone(),
+                    None => vec![sensor.id().to_string()],
+                })
+                .collect()
+        };
         let mut curves: HashMap<String, CurveContainer> = HashMap::new();
-        for curveconf in &config.curves {
+        for curveconf in config.sorted_curves().unwrap() {
             let id = curveconf.id.clone();
             info!("Loading curve {id}");
             match &curveconf.function {
                 config::CurveFunction::linear(curve) => {
                     let sensor_id = &curve.sensor;
                     info!("Searching for {}", sensor_id);
-                    let sensor = get_sensor(sensor_id, sensors, &curves);
+                    let sensor = get_sensor(sensor_id, sensors, &curves, fan_readings);
                     curves.insert(
                         id,
                         Arc::new(Mutex::new(curve::LinearCurve::new(sensor, curve))),
@@ -90,8 +181,8 @@ impl FanHub {
                 }
                 config::CurveFunction::maximum(curve) => {
                     let mut mc_sensors: Vec<ReadableValueContainer> = vec![];
-                    for sensor_id in &curve.sensors {
-                        let sensor = get_sensor(sensor_id, sensors, &curves);
+                    for sensor_id in expand(&curve.sensors) {
+                        let sensor = get_sensor(&sensor_id, sensors, &curves, fan_readings);
                         mc_sensors.push(sensor);
                     }
                     let mc = curve::MaximumCurve {
@@ -101,8 +192,8 @@ impl FanHub {
                 }
                 config::CurveFunction::average(curve) => {
                     let mut ac_sensors: Vec<ReadableValueContainer> = vec![];
-                    for sensor_id in &curve.sensors {
-                        let sensor = get_sensor(sensor_id, sensors, &curves);
+                    for sensor_id in expand(&curve.sensors) {
+                        let sensor = get_sensor(&sensor_id, sensors, &curves, fan_readings);
                         ac_sensors.push(sensor);
                     }
                     let ac = curve::AverageCurve {
@@ -112,11 +203,82 @@ impl FanHub {
                 }
                 config::CurveFunction::pid(curve) => {
                     let sensor_id = &curve.sensor;
-                    let sensor = get_sensor(sensor_id, sensors, &curves);
-                    let pid_curve =
-                        curve::PidCurve::new(sensor, curve.p, curve.i, curve.d, curve.target);
+                    let sensor = get_sensor(sensor_id, sensors, &curves, fan_readings);
+                    let pid_curve = curve::PidCurve::new(sensor, curve);
                     curves.insert(id, Arc::new(Mutex::new(pid_curve)));
                 }
+                config::CurveFunction::switch(curve) => {
+                    let conditions = curve
+                        .conditions
+                        .iter()
+                        .map(|condition| {
+                            let sensor =
+                                get_sensor(&condition.sensor, sensors, &curves, fan_readings);
+                            curve::SwitchCondition::new(sensor, condition)
+                        })
+                        .collect();
+                    let targets = curve
+                        .conditions
+                        .iter()
+                        .map(|condition| &condition.curve)
+                        .chain(std::iter::once(&curve.default))
+                        .map(|curve_id| get_sensor(curve_id, sensors, &curves, fan_readings))
+                        .collect();
+                    let switch_curve = curve::SwitchCurve::new(conditions, targets);
+                    curves.insert(id, Arc::new(Mutex::new(switch_curve)));
+                }
+                config::CurveFunction::schedule(curve) => {
+                    let targets: Vec<ReadableValueContainer> = curve
+                        .rules
+                        .iter()
+                        .map(|rule| &rule.target)
+                        .chain(std::iter::once(&curve.default))
+                        .map(|target| match target {
+                            config::ScheduleTarget::Curve(sensor_id) => {
+                                get_sensor(sensor_id, sensors, &curves, fan_readings)
+                            }
+                            config::ScheduleTarget::Value(value) => {
+                                Arc::new(Mutex::new(curve::StaticCurve { value: *value }))
+                            }
+                        })
+                        .collect();
+                    let schedule_curve = curve::ScheduleCurve::new(curve, targets);
+                    curves.insert(id, Arc::new(Mutex::new(schedule_curve)));
+                }
+                config::CurveFunction::delay(curve) => {
+                    let sensor = get_sensor(&curve.sensor, sensors, &curves, fan_readings);
+                    let delay_curve = curve::DelayCurve::new(sensor, curve);
+                    curves.insert(id, Arc::new(Mutex::new(delay_curve)));
+                }
+                config::CurveFunction::peak_hold(curve) => {
+                    let sensor = get_sensor(&curve.sensor, sensors, &curves, fan_readings);
+                    let peak_curve = curve::PeakHoldCurve::new(sensor, curve);
+                    curves.insert(id, Arc::new(Mutex::new(peak_curve)));
+                }
+                config::CurveFunction::delta(curve) => {
+                    let sensor = get_sensor(&curve.sensor, sensors, &curves, fan_readings);
+                    let ambient = get_sensor(&curve.ambient, sensors, &curves, fan_readings);
+                    let delta_curve = curve::DeltaCurve::new(sensor, ambient, curve);
+                    curves.insert(id, Arc::new(Mutex::new(delta_curve)));
+                }
+                config::CurveFunction::ramp(curve) => {
+                    let sensor = get_sensor(&curve.sensor, sensors, &curves, fan_readings);
+                    let ramp_curve = curve::RampCurve::new(sensor, curve);
+                    curves.insert(id, Arc::new(Mutex::new(ramp_curve)));
+                }
+                config::CurveFunction::expression(curve) => {
+                    let mut ec_sensors: HashMap<String, ReadableValueContainer> = HashMap::new();
+                    for (sensor_id, _column) in curve.expression.identifiers() {
+                        let sensor = get_sensor(sensor_id, sensors, &curves, fan_readings);
+                        ec_sensors.insert(sensor_id.to_string(), sensor);
+                    }
+                    let ec = curve::ExpressionCurve {
+                        expression: curve.expression.clone(),
+                        unit: curve.unit.unwrap_or(SensorType::PERCENTAGE),
+                        sensors: ec_sensors,
+                    };
+                    curves.insert(id, Arc::new(Mutex::new(ec)));
+                }
             }
         }
         curves
@@ -125,84 +287,202 @@ impl FanHub {
     fn load_fans(
         config: &RufacoConfig,
         curves: &HashMap<String, CurveContainer>,
+        fan_readings: &HashMap<String, FanReadingsContainer>,
         hwmons: &Hwmons,
-    ) -> HashMap<String, FanContainer> {
+    ) -> Result<HashMap<String, FanContainer>, String> {
         let mut fans: HashMap<String, FanContainer> = HashMap::new();
         for sensorconf in &config.fans {
             match &sensorconf.sensor {
                 config::SensorType::hwmon(conf) => {
                     let (fan_sensor, pwm_sensor) =
-                        hwmon::load_hwmon_fan(hwmons, &conf.chip, &conf.name);
-                    let curve = curves[&sensorconf.curve].clone();
+                        hwmon::load_hwmon_fan(hwmons, &conf.chip, &conf.name, conf.pwm.as_deref())
+                            .map_err(|err| format!("Couldn't load fan {}: {err}", sensorconf.id))?;
+                    let mut fan_curves: Vec<(String, CurveContainer)> = sensorconf
+                        .curve
+                        .ids()
+                        .iter()
+                        .map(|curve_id| (curve_id.to_string(), curves[*curve_id].clone()))
+                        .collect();
+                    if let Some(group) = config.fan_group(&sensorconf.id) {
+                        let member = group
+                            .fans
+                            .iter()
+                            .find(|member| member.fan == sensorconf.id)
+                            .unwrap();
+                        let curve_id = group.curve.id();
+                        fan_curves.push((
+                            curve_id.to_string(),
+                            Arc::new(Mutex::new(curve::ScaledCurve {
+                                sensor: curves[curve_id].clone(),
+                                scale: member.scale,
+                                offset: member.offset,
+                            })),
+                        ));
+                    }
                     info!("Fan sensor: {:?}", conf);
                     let rufaco_sensor = Arc::new(Mutex::new(FanSensor::new(
                         sensorconf,
-                        fan_sensor.unwrap(),
-                        pwm_sensor.unwrap(),
-                        curve,
+                        fan_sensor,
+                        pwm_sensor,
+                        fan_curves,
+                        fan_readings[&sensorconf.id].clone(),
                     )));
                     let id = rufaco_sensor.lock().unwrap().id.clone();
                     fans.insert(id, rufaco_sensor);
                 }
                 config::SensorType::file(_path) => todo!(),
+                // Rejected by the config validation
+                config::SensorType::glob(_) => unreachable!("Fans can't use glob sensors"),
             }
         }
-        fans
-    }
-
-    fn setup_fans(&mut self, measure_delay: u64, running: Arc<AtomicBool>) {
-        // Create the fan parameters if they don't exist
-        self.fans.iter().for_each(|(_id, fan_mutex)| {
-            let mut fan = fan_mutex.lock().unwrap();
-            let conf = self.config.fans.iter_mut().find(|val| val.id == fan.id);
-            match conf {
-                Some(conf) => {
-                    // If any of the pwm settings is none we create them.
-                    if conf.minpwm.is_none() || conf.startpwm.is_none() {
-                        warn!(
-                            "Fan {} does not have minpwm or startpwm configured. Measuring now...",
-                            fan.id
-                        );
-                        let (min_pwm, start_pwm) = fan
-                            .measure_fan(Duration::from_millis(measure_delay), 30, running.clone())
-                            .unwrap();
-                        conf.minpwm = Some(min_pwm);
-                        conf.startpwm = Some(start_pwm);
-                        // Write config
-                        let conf_string = serde_yaml::to_string(&self.config).unwrap();
-                        debug!("Writing config {:?}", conf_string);
-                        let mut f = std::fs::OpenOptions::new()
-                            .write(true)
-                            .create(true)
-                            .truncate(true)
-                            .open("config.yaml")
-                            .expect("Couldn't open config file");
-                        write!(f, "{}", conf_string).unwrap();
-                    }
-                }
-                None => error!(
-                    "Unable to find config with id {}. This should never happen!",
-                    fan.id
-                ),
-            }
-        });
+        Ok(fans)
+    }
+
+    fn load_fan_groups(
+        config: &RufacoConfig,
+        fans: &HashMap<String, FanContainer>,
+    ) -> Vec<FanGroup> {
+        config
+            .fan_groups
+            .iter()
+            .map(|group| FanGroup {
+                id: group.id.clone(),
+                members: group
+                    .fans
+                    .iter()
+                    .map(|member| fans[&member.fan].clone())
+                    .collect(),
+                on_failure: group.on_failure,
+                failed: vec![],
+            })
+            .collect()
     }
 
-    pub fn new(config: RufacoConfig, measure_delay: u64, running: Arc<AtomicBool>) -> Self {
+    /// Loads the sensors, curves and fans of the config. Fails if a fan can't be found
+    pub fn new(config: RufacoConfig) -> Result<Self, String> {
         let hwmons = parse_hwmons().unwrap();
-        let sensors = FanHub::load_sensors(&config, &hwmons);
-        let curves = FanHub::load_curves(&config, &sensors);
-        let fans = FanHub::load_fans(&config, &curves, &hwmons);
-        let mut new_fanhub = Self {
+        let (sensors, groups) = FanHub::load_sensors(&config, &hwmons);
+        let fan_readings = FanHub::load_fan_readings(&config);
+        let curves = FanHub::load_curves(&config, &sensors, &groups, &fan_readings);
+        let fans = FanHub::load_fans(&config, &curves, &fan_readings, &hwmons)?;
+        let fan_groups = FanHub::load_fan_groups(&config, &fans);
+        // The fans return to the configured shutdown state once they are dropped
+        for fan in fans.values() {
+            fan.lock().unwrap().take_control();
+        }
+        let curve_order = config
+            .sorted_curves()
+            .unwrap()
+            .iter()
+            .map(|curve| curve.id.clone())
+            .collect();
+        Ok(Self {
             config,
             sensors,
             curves,
+            curve_order,
             fans,
-        };
+            fan_groups,
+        })
+    }
 
-        new_fanhub.setup_fans(measure_delay, running);
+    /// Ids of the fans with tach but without minpwm or startpwm
+    pub fn uncalibrated_fans(&self) -> Vec<String> {
+        self.config
+            .fans
+            .iter()
+            .filter(|fan| fan.minpwm.is_none() || fan.startpwm.is_none())
+            .filter(|fan| self.fans[&fan.id].lock().unwrap().has_tach())
+            .map(|fan| fan.id.clone())
+            .collect()
+    }
+
+    /// Drops the fans without tach from `fan_ids`. They can't be calibrated
+    pub fn fans_with_tach(&self, fan_ids: &[String]) -> Vec<String> {
+        fan_ids
+            .iter()
+            .filter(|fan_id| {
+                let has_tach = self.fans[*fan_id].lock().unwrap().has_tach();
+                if !has_tach {
+                    info!("Skipping fan {fan_id} without tach");
+                }
+                has_tach
+            })
+            .cloned()
+            .collect()
+    }
 
-        new_fanhub
+    /// Groups the fans by the chip controlling them. Fans of different chips are independent
+    fn fans_by_chip(&self, fan_ids: &[String]) -> Vec<ChipFans> {
+        let mut groups: Vec<(Option<&str>, ChipFans)> = vec![];
+        for fan_id in fan_ids {
+            let Some(fan) = self.fans.get(fan_id) else {
+                error!("Unknown fan {fan_id}");
+                continue;
+            };
+            let chip = self
+                .config
+                .fans
+                .iter()
+                .find(|conf| conf.id == *fan_id)
+                .and_then(|conf| match &conf.sensor {
+                    config::SensorType::hwmon(hwmon) => Some(hwmon.chip.as_str()),
+                    _ => None,
+                });
+            let member = (fan_id.clone(), fan.clone());
+            match groups
+                .iter_mut()
+                .find(|(group_chip, _)| chip.is_some() && *group_chip == chip)
+            {
+                Some((_, members)) => members.push(member),
+                None => groups.push((chip, vec![member])),
+            }
+        }
+        groups.into_iter().map(|(_, members)| members).collect()
+    }
+
+    /// Measures the fans. The fans use the results right away and are set back to their
+    /// previous output afterwards. Fans on the same chip are measured one after another,
+    /// independent chips are measured concurrently if `parallel` is set.
+    /// Returns the results of all fans that were measured
+    pub fn calibrate(
+        &self,
+        fan_ids: &[String],
+        settle: &SettleCriteria,
+        parallel: bool,
+        running: Arc<AtomicBool>,
+    ) -> Vec<(String, Result<FanMeasurement, CalibrationError>)> {
+        let total = fan_ids.len();
+        let started = AtomicUsize::new(0);
+        let calibrate_group = |group: &ChipFans| {
+            let mut results = vec![];
+            for (fan_id, fan) in group {
+                if !running.load(Ordering::SeqCst) {
+                    break;
+                }
+                let index = started.fetch_add(1, Ordering::SeqCst) + 1;
+                info!("Calibrating fan {fan_id} ({index}/{total})");
+                let mut fan = fan.lock().unwrap();
+                let original_pwm = fan.fan_pwm.get_output();
+                let measurement = fan.measure_fan(settle, &running);
+                fan.fan_pwm.set_output(original_pwm);
+                match &measurement {
+                    Ok(measurement) => info!(
+                        "Fan {fan_id} has min pwm {}, start pwm {} and reaches {} rpm",
+                        measurement.min_pwm, measurement.start_pwm, measurement.calibration.max_rpm
+                    ),
+                    Err(err) => error!("Calibration of fan {fan_id} failed: {err}"),
+                }
+                results.push((fan_id.clone(), measurement));
+            }
+            results
+        };
+        let groups = self.fans_by_chip(fan_ids);
+        if parallel {
+            groups.par_iter().flat_map(calibrate_group).collect()
+        } else {
+            groups.iter().flat_map(calibrate_group).collect()
+        }
     }
 
     pub fn update(&mut self) {
@@ -210,14 +490,65 @@ impl FanHub {
         self.sensors.iter_mut().for_each(|(_id, sensor)| {
             sensor.lock().unwrap().update_input();
         });
+        // Fan speeds can be inputs of curves as well
+        self.fans.iter().for_each(|(_id, fan)| {
+            fan.lock().unwrap().update_input();
+        });
 
-        self.curves.iter().for_each(|(_id, curve)| {
-            curve.lock().unwrap().update_value();
+        self.curve_order.iter().for_each(|id| {
+            self.curves[id].lock().unwrap().update_value();
         });
 
-        // Then update all fans
+        // Then update all fans. Members of a group start together
+        self.fan_groups.iter_mut().for_each(|group| {
+            group.sync_start();
+            group.compensate();
+        });
         self.fans.iter().for_each(|(_id, fan)| {
             fan.lock().unwrap().update_output();
         });
     }
+
+    /// Returns the status of all fans ordered by id
+    pub fn status(&self) -> Vec<FanStatus> {
+        let mut status: Vec<FanStatus> = self
+            .fans
+            .values()
+            .map(|fan| fan.lock().unwrap().status())
+            .collect();
+        status.sort_by(|a, b| a.id.cmp(&b.id));
+        status
+    }
+
+    /// Runs the configured experiment on the fan and suggests PID gains for the sensor.
+    /// The fan is set back to its previous output afterwards
+    pub fn autotune(
+        &self,
+        fan_id: &str,
+        sensor_id: &str,
+        conf: &AutotuneConfig,
+        running: Arc<AtomicBool>,
+    ) -> Option<Gains> {
+        let Some(fan) = self.fans.get(fan_id) else {
+            error!("Unknown fan {fan_id}");
+            return None;
+        };
+        let Some(sensor) = self.sensors.get(sensor_id) else {
+            error!("Unknown sensor {sensor_id}");
+            return None;
+        };
+        let original_pwm = fan.lock().unwrap().fan_pwm.get_output();
+        let mut process = AutotuneProcess {
+            fan: fan.clone(),
+            sensor: sensor.clone(),
+            interval: conf.interval,
+        };
+        info!(
+            "Running {:?} experiment on fan {fan_id} with sensor {sensor_id}",
+            conf.experiment
+        );
+        let model = autotune::run_experiment(&mut process, conf, running);
+        fan.lock().unwrap().fan_pwm.set_output(original_pwm);
+        autotune::suggest_gains(&model?, conf.rule)
+    }
 }
@@ -1,8 +1,11 @@
 use log::info;
 
-use crate::fan::{FanInput, FanOutput, HwmonFan, HwmonPwm};
+use crate::{
+    detect::{PwmCandidate, TachCandidate},
+    fan::{FanInput, FanOutput, HwmonFan, HwmonPwm},
+};
 use libmedium::{
-    hwmon::sync_hwmon::Hwmons,
+    hwmon::sync_hwmon::{Hwmon, Hwmons},
     sensors::{
         sync_sensors::{temp, SyncSensor},
         Sensor,
@@ -13,7 +16,7 @@ pub fn load_hwmon_sensor(
     hwmons: &Hwmons,
     chip_name: &String,
     sensor_name: &String,
-) -> Option<Box<dyn temp::TempSensor>> {
+) -> Option<Box<dyn temp::TempSensor + Send>> {
     // Load hwmon
     info!("Loading hwmon config with name {}", chip_name);
     for hwmon in hwmons.hwmons_by_name(chip_name) {
@@ -28,24 +31,191 @@ pub fn load_hwmon_sensor(
     None
 }
 
-type FanInputOutput = (Option<Box<dyn FanInput>>, Option<Box<dyn FanOutput>>);
-pub fn load_hwmon_fan(hwmons: &Hwmons, chip_name: &String, sensor_name: &String) -> FanInputOutput {
+/// Matches the text against a glob where `*` matches any number of characters and `?` a single one
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    // Position in the pattern after the last `*` and the text position it was matched against
+    let mut backtrack: Option<(usize, usize)> = None;
+    let (mut p, mut t) = (0, 0);
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                p += 1;
+                backtrack = Some((p, t));
+            }
+            Some(c) if *c == '?' || *c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                // Let the last `*` consume one more character
+                Some((star_p, star_t)) => {
+                    p = star_p;
+                    t = star_t + 1;
+                    backtrack = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Loads all temperature sensors whose chip and label match the globs.
+/// Returns the sensors with a `chip/label` description for logging
+pub fn load_hwmon_sensor_group(
+    hwmons: &Hwmons,
+    chip_glob: &str,
+    label_glob: &str,
+) -> Vec<(String, Box<dyn temp::TempSensor + Send>)> {
+    let mut sensors: Vec<(String, Box<dyn temp::TempSensor + Send>)> = vec![];
+    for hwmon in hwmons {
+        if !glob_match(chip_glob, hwmon.name()) {
+            continue;
+        }
+        for temp in hwmon.temps().values() {
+            if glob_match(label_glob, &temp.name()) {
+                info!("Matched hwmon {} and sensor {}", hwmon.name(), temp.name());
+                sensors.push((
+                    format!("{}/{}", hwmon.name(), temp.name()),
+                    Box::new(temp.clone()),
+                ));
+            }
+        }
+    }
+    sensors
+}
+
+type FanInputOutput = (Option<Box<dyn FanInput>>, Box<dyn FanOutput>);
+
+/// Loads the tach and pwm of a fan. Fails if the fan or its pwm doesn't exist
+pub fn load_hwmon_fan(
+    hwmons: &Hwmons,
+    chip_name: &str,
+    sensor_name: &str,
+    pwm_name: Option<&str>,
+) -> Result<FanInputOutput, String> {
     // Load hwmon
     info!("Loading hwmon config with name {}", chip_name);
     for hwmon in hwmons.hwmons_by_name(chip_name) {
         info!("Loading hwmon {:?}", hwmon.name());
         for temp in hwmon.writeable_fans().values() {
-            if sensor_name == &temp.name() {
+            if sensor_name == temp.name() {
                 info!("Matched hwmon {} and sensor {}", hwmon.name(), temp.name());
                 let fan_input = Box::new(HwmonFan {
                     fan_input: Box::new(temp.clone()),
                 });
+                let pwm_index = match pwm_name {
+                    Some(pwm_name) => hwmon
+                        .writeable_pwms()
+                        .values()
+                        .find(|pwm| pwm.name() == pwm_name)
+                        .map(|pwm| pwm.index())
+                        .ok_or(format!(
+                            "Chip {chip_name} has no writeable pwm {pwm_name} for fan {sensor_name}"
+                        ))?,
+                    None => temp.index(),
+                };
+                let pwm = hwmon.writeable_pwm(pwm_index).ok_or(format!(
+                    "Chip {chip_name} has no writeable pwm{pwm_index} for fan {sensor_name}"
+                ))?;
                 let fan_pwm = Box::new(HwmonPwm {
-                    fan_pwm: Box::new(hwmon.writeable_pwm(temp.index()).unwrap().clone()),
+                    fan_pwm: Box::new(pwm.clone()),
                 });
-                return (Some(fan_input), Some(fan_pwm));
+                return Ok((Some(fan_input), fan_pwm));
             }
         }
+        // Fans without tach are configured by the name of their pwm
+        if let Some(pwm) = hwmon
+            .writeable_pwms()
+            .values()
+            .find(|pwm| sensor_name == pwm.name())
+        {
+            info!(
+                "Matched hwmon {} and pwm {} without tach",
+                hwmon.name(),
+                pwm.name()
+            );
+            let fan_pwm = Box::new(HwmonPwm {
+                fan_pwm: Box::new(pwm.clone()),
+            });
+            return Ok((None, fan_pwm));
+        }
+    }
+    Err(format!("Chip {chip_name} has no fan or pwm {sensor_name}"))
+}
+
+/// Returns every writeable pwm and tach of the chip for the detection
+pub fn detect_candidates(hwmon: &Hwmon) -> (Vec<PwmCandidate>, Vec<TachCandidate>) {
+    let pwms = hwmon
+        .writeable_pwms()
+        .values()
+        .map(|pwm| PwmCandidate {
+            name: pwm.name(),
+            output: Box::new(HwmonPwm {
+                fan_pwm: Box::new(pwm.clone()),
+            }),
+        })
+        .collect();
+    let tachs = hwmon
+        .writeable_fans()
+        .values()
+        .map(|fan| TachCandidate {
+            name: fan.name(),
+            input: Box::new(HwmonFan {
+                fan_input: Box::new(fan.clone()),
+            }),
+        })
+        .collect();
+    (pwms, tachs)
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use libmedium::hwmon::sync_hwmon::Hwmons;
+
+    use super::{glob_match, load_hwmon_fan};
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("coretemp", "coretemp"));
+        assert!(!glob_match("coretemp", "coretemp2"));
+        assert!(glob_match("Core *", "Core 12"));
+        assert!(!glob_match("Core *", "Package id 0"));
+        assert!(glob_match("nvme*", "nvme"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("k10temp?", "k10temp1"));
+        assert!(!glob_match("k10temp?", "k10temp"));
+        assert!(glob_match("*a*b", "xaxxab"));
+        assert!(!glob_match("*a*b", "xaxxa"));
+    }
+
+    #[test]
+    fn test_load_hwmon_fan() {
+        let dir = std::env::temp_dir().join(format!("rufaco_hwmon_{}", std::process::id()));
+        let hwmon = dir.join("hwmon0");
+        fs::create_dir_all(&hwmon).unwrap();
+        fs::write(hwmon.join("name"), "nct6775\n").unwrap();
+        fs::write(hwmon.join("fan1_input"), "1000\n").unwrap();
+        fs::write(hwmon.join("pwm1"), "128\n").unwrap();
+        fs::write(hwmon.join("pwm2"), "128\n").unwrap();
+        let hwmons = Hwmons::parse_unrestricted(&dir).unwrap();
+
+        let (fan_input, _fan_pwm) = load_hwmon_fan(&hwmons, "nct6775", "fan1", None).unwrap();
+        assert!(fan_input.is_some());
+        assert!(load_hwmon_fan(&hwmons, "nct6775", "fan1", Some("pwm2")).is_ok());
+        // A typo in the pwm name is reported instead of crashing later
+        assert_eq!(
+            load_hwmon_fan(&hwmons, "nct6775", "fan1", Some("pwm3")).err(),
+            Some("Chip nct6775 has no writeable pwm pwm3 for fan fan1".to_string())
+        );
+        assert_eq!(
+            load_hwmon_fan(&hwmons, "nct6775", "fan4", None).err(),
+            Some("Chip nct6775 has no fan or pwm fan4".to_string())
+        );
+        fs::remove_dir_all(&dir).unwrap();
     }
-    (None, None)
 }
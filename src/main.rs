@@ -1,6 +1,9 @@
-use clap::Parser;
+use autotune::{AutotuneConfig, Experiment, TuningRule};
+use clap::{Parser, Subcommand};
+use fan::{FanMeasurement, SettleCriteria};
 use fanhub::FanHub;
-use log::{debug, info};
+use libmedium::parse_hwmons;
+use log::{debug, error, info, warn};
 use simplelog::{ColorChoice, TermLogger, TerminalMode};
 
 use std::{
@@ -11,12 +14,17 @@ use std::{
     thread, time,
 };
 
+mod autotune;
 mod common;
 mod config;
 mod curve;
+mod detect;
+mod expression;
 mod fan;
+mod fangroup;
 mod fanhub;
 mod hwmon;
+mod pid;
 mod temperature;
 
 use signal_hook::{
@@ -30,8 +38,239 @@ struct Args {
     /// Delay between fan measurements
     #[arg(long, default_value_t = 1000)]
     measure_delay: u64,
+    /// Number of fan measurements that have to agree during calibration
+    #[arg(long, default_value_t = 5)]
+    settle_window: usize,
+    /// Maximum deviation in rpm of the measurements from their mean during calibration
+    #[arg(long, default_value_t = 30)]
+    settle_tolerance: i32,
+    /// Maximum time in seconds to wait for the rpm to settle at a pwm during calibration
+    #[arg(long, default_value_t = 60)]
+    settle_timeout: u64,
+    /// Calibrate fans of different chips at the same time
+    #[arg(long, default_value_t = false)]
+    parallel_calibration: bool,
     #[arg(short, long, default_value_t = false)]
     verbose: bool,
+    /// Log the status of all fans every given number of seconds
+    #[arg(long)]
+    status_interval: Option<u64>,
+    /// Calibrate fans without minpwm or startpwm at startup instead of using safe defaults
+    #[arg(long, default_value_t = false)]
+    auto_calibrate: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run an experiment on a fan and suggest PID gains for a sensor
+    Autotune(AutotuneArgs),
+    /// Measure the pwm range of fans and write the results to the config
+    Calibrate(CalibrateArgs),
+    /// Find out which pwm outputs drive which fan inputs
+    Detect(DetectArgs),
+}
+
+#[derive(clap::Args)]
+struct DetectArgs {
+    /// Time in seconds to wait for the fans to react to a pwm change
+    #[arg(long, default_value_t = 5)]
+    wait: u64,
+    /// Write a config skeleton with the detected fans to this path
+    #[arg(long)]
+    write: Option<String>,
+}
+
+#[derive(clap::Args)]
+struct CalibrateArgs {
+    /// Ids of the fans to calibrate. Calibrates all fans if empty
+    fans: Vec<String>,
+    /// Only show which fans would be calibrated
+    #[arg(long, default_value_t = false)]
+    dry_run: bool,
+}
+
+#[derive(clap::Args)]
+struct AutotuneArgs {
+    /// Id of the fan to drive
+    fan: String,
+    /// Id of the sensor to observe
+    sensor: String,
+    #[arg(long, value_enum, default_value_t = Experiment::Relay)]
+    experiment: Experiment,
+    #[arg(long, value_enum, default_value_t = TuningRule::ZieglerNichols)]
+    rule: TuningRule,
+    /// Lower fan output in percent
+    #[arg(long, default_value_t = 30.0)]
+    low: f64,
+    /// Upper fan output in percent
+    #[arg(long, default_value_t = 100.0)]
+    high: f64,
+    /// Hysteresis of the relay around the starting temperature
+    #[arg(long, default_value_t = 0.5)]
+    hysteresis: f64,
+    /// Number of relay oscillations to analyze
+    #[arg(long, default_value_t = 3)]
+    cycles: usize,
+    /// Delay between samples in milliseconds
+    #[arg(long, default_value_t = 1000)]
+    interval: u64,
+    /// Maximum duration of the experiment in seconds
+    #[arg(long, default_value_t = 3600)]
+    timeout: u64,
+    /// Maximum change of the sensor during the settle window to count as settled
+    #[arg(long, default_value_t = 0.2)]
+    settle_tolerance: f64,
+    /// Duration in seconds the sensor has to be stable in a step experiment
+    #[arg(long, default_value_t = 60)]
+    settle_window: u64,
+    /// Write the gains to the PID curve with this id
+    #[arg(long)]
+    curve: Option<String>,
+}
+
+fn autotune(fan_hub: &FanHub, args: &AutotuneArgs, config_path: &str, running: Arc<AtomicBool>) {
+    let conf = AutotuneConfig {
+        experiment: args.experiment,
+        rule: args.rule,
+        low: args.low,
+        high: args.high,
+        hysteresis: args.hysteresis,
+        cycles: args.cycles,
+        interval: time::Duration::from_millis(args.interval),
+        timeout: time::Duration::from_secs(args.timeout),
+        settle_tolerance: args.settle_tolerance,
+        settle_window: time::Duration::from_secs(args.settle_window),
+    };
+    let Some(gains) = fan_hub.autotune(&args.fan, &args.sensor, &conf, running) else {
+        error!("Autotune failed");
+        return;
+    };
+    info!(
+        "Suggested gains using {:?}: p: {} i: {} d: {}",
+        args.rule, gains.p, gains.i, gains.d
+    );
+    if let Some(curve_id) = &args.curve {
+        let mut rufaco_conf = match config::read_config(config_path) {
+            Ok(conf) => conf,
+            Err(err) => {
+                error!("Couldn't write gains: {err}");
+                return;
+            }
+        };
+        if rufaco_conf.set_pid_gains(curve_id, gains.p as f32, gains.i as f32, gains.d as f32) {
+            config::save_config(&rufaco_conf, config_path);
+            info!("Wrote gains to curve {curve_id} in {config_path}");
+        } else {
+            error!("Config has no pid curve {curve_id}");
+        }
+    }
+}
+
+/// Detects the pwm of every fan and prints the pairings or writes them as config skeleton
+fn detect(args: &DetectArgs, running: Arc<AtomicBool>) {
+    let hwmons = parse_hwmons().unwrap();
+    let wait = time::Duration::from_secs(args.wait);
+    let mut chips = vec![];
+    for hwmon in &hwmons {
+        let (mut pwms, tachs) = hwmon::detect_candidates(hwmon);
+        if pwms.is_empty() || tachs.is_empty() {
+            continue;
+        }
+        info!("Detecting fans of {}", hwmon.name());
+        let pairings = detect::detect_pairings(&mut pwms, &tachs, wait, &running);
+        for pairing in &pairings {
+            println!(
+                "{}: {} -> {}",
+                hwmon.name(),
+                pairing.pwm,
+                match pairing.tachs.is_empty() {
+                    true => "no fan".to_string(),
+                    false => pairing.tachs.join(", "),
+                }
+            );
+        }
+        chips.push((hwmon.name().to_string(), pairings));
+    }
+    if let Some(path) = &args.write {
+        config::save_config(&detect::config_skeleton(&chips), path);
+        info!("Wrote config skeleton to {path}");
+    }
+}
+
+/// Returns the fans to calibrate or None if an id is unknown.
+/// Without ids all fans are returned. Fans without tach are only known once the fans are loaded
+fn calibration_plan(conf: &config::RufacoConfig, fan_ids: &[String]) -> Option<Vec<String>> {
+    if fan_ids.is_empty() {
+        return Some(conf.fans.iter().map(|fan| fan.id.clone()).collect());
+    }
+    for fan_id in fan_ids {
+        if !conf.fans.iter().any(|fan| fan.id == *fan_id) {
+            error!("Config has no fan {fan_id}");
+            return None;
+        }
+    }
+    Some(fan_ids.to_vec())
+}
+
+fn print_calibration_plan(conf: &config::RufacoConfig, fan_ids: &[String], config_path: &str) {
+    for fan in conf.fans.iter().filter(|fan| fan_ids.contains(&fan.id)) {
+        info!(
+            "Would calibrate fan {} (minpwm {:?}, startpwm {:?})",
+            fan.id, fan.minpwm, fan.startpwm
+        );
+    }
+    info!("Results would be written to {config_path}");
+}
+
+/// Calibrates the fans and writes the results to the config
+fn calibrate(
+    fan_hub: &FanHub,
+    fan_ids: &[String],
+    args: &Args,
+    config_path: &str,
+    running: Arc<AtomicBool>,
+) {
+    let settle = SettleCriteria {
+        interval: time::Duration::from_millis(args.measure_delay),
+        window: args.settle_window,
+        tolerance: args.settle_tolerance,
+        timeout: time::Duration::from_secs(args.settle_timeout),
+    };
+    let results = fan_hub.calibrate(fan_ids, &settle, args.parallel_calibration, running);
+    let failed: Vec<&str> = results
+        .iter()
+        .filter(|(_fan_id, result)| result.is_err())
+        .map(|(fan_id, _result)| fan_id.as_str())
+        .collect();
+    if !failed.is_empty() {
+        error!("Calibration failed for fans {}", failed.join(", "));
+    }
+    let measurements: Vec<(String, FanMeasurement)> = results
+        .into_iter()
+        .filter_map(|(fan_id, result)| result.ok().map(|measurement| (fan_id, measurement)))
+        .collect();
+    if measurements.is_empty() {
+        return;
+    }
+    let mut rufaco_conf = match config::read_config(config_path) {
+        Ok(conf) => conf,
+        Err(err) => {
+            error!("Couldn't write calibration: {err}");
+            return;
+        }
+    };
+    for (fan_id, measurement) in measurements {
+        rufaco_conf.set_calibration(
+            &fan_id,
+            measurement.min_pwm,
+            measurement.start_pwm,
+            measurement.calibration,
+        );
+    }
+    config::save_config(&rufaco_conf, config_path);
+    info!("Wrote calibration to {config_path}");
 }
 
 fn main() {
@@ -72,9 +311,6 @@ fn main() {
     });
 
     let running = Arc::new(AtomicBool::new(true));
-    let rufaco_conf = config::load_config(selected_config.unwrap());
-    let mut fan_hub = FanHub::new(rufaco_conf, args.measure_delay, running.clone());
-
     let mut stop_signal = Signals::new([SIGTERM, SIGINT]).unwrap();
     let running_copy = running.clone();
 
@@ -85,9 +321,78 @@ fn main() {
         }
     });
 
+    if let Some(Command::Detect(detect_args)) = &args.command {
+        detect(detect_args, running);
+        return;
+    }
+
+    let config_path = selected_config.unwrap();
+    let rufaco_conf = match config::load_config(config_path) {
+        Ok(conf) => conf,
+        Err(err) => {
+            error!("{err}");
+            std::process::exit(1);
+        }
+    };
+
+    let calibrate_fans = match &args.command {
+        Some(Command::Calibrate(calibrate_args)) => {
+            let Some(fan_ids) = calibration_plan(&rufaco_conf, &calibrate_args.fans) else {
+                return;
+            };
+            if calibrate_args.dry_run {
+                print_calibration_plan(&rufaco_conf, &fan_ids, config_path);
+                return;
+            }
+            fan_ids
+        }
+        _ => vec![],
+    };
+
+    let mut fan_hub = match FanHub::new(rufaco_conf) {
+        Ok(fan_hub) => fan_hub,
+        Err(err) => {
+            error!("{err}");
+            std::process::exit(1);
+        }
+    };
+
+    match &args.command {
+        Some(Command::Autotune(autotune_args)) => {
+            autotune(&fan_hub, autotune_args, config_path, running);
+            return;
+        }
+        Some(Command::Calibrate(calibrate_args)) => {
+            // Fans without tach are skipped unless they were requested explicitly
+            let fan_ids = match calibrate_args.fans.is_empty() {
+                true => fan_hub.fans_with_tach(&calibrate_fans),
+                false => calibrate_fans,
+            };
+            calibrate(&fan_hub, &fan_ids, &args, config_path, running);
+            return;
+        }
+        Some(Command::Detect(_)) | None => {}
+    }
+
+    let uncalibrated = fan_hub.uncalibrated_fans();
+    if args.auto_calibrate && !uncalibrated.is_empty() {
+        calibrate(&fan_hub, &uncalibrated, &args, config_path, running.clone());
+    } else if !uncalibrated.is_empty() {
+        warn!(
+            "Fans {} are not calibrated. Run the calibrate command or pass --auto-calibrate",
+            uncalibrated.join(", ")
+        );
+    }
+
     // Update
+    let status_interval = args.status_interval.map(time::Duration::from_secs);
+    let mut last_status = time::Instant::now();
     while running.load(Ordering::SeqCst) {
         fan_hub.update();
+        if status_interval.is_some_and(|interval| last_status.elapsed() >= interval) {
+            fan_hub.status().iter().for_each(|status| info!("{status}"));
+            last_status = time::Instant::now();
+        }
         let sleep_duration = time::Duration::from_millis(100);
         thread::sleep(sleep_duration);
     }
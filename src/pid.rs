@@ -0,0 +1,249 @@
+use crate::config::{self, AntiWindup, PidDirection};
+
+/// Output of a single controller step including the individual terms
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PidOutput {
+    pub output: f32,
+    pub p: f32,
+    pub i: f32,
+    pub d: f32,
+}
+
+pub struct PidController {
+    pub target: f32,
+    kp: f32,
+    ki: f32,
+    kd: f32,
+    direction: PidDirection,
+    output_min: f32,
+    output_max: f32,
+    p_limit: Option<f32>,
+    i_limit: Option<f32>,
+    d_limit: Option<f32>,
+    anti_windup: AntiWindup,
+    tracking_gain: f32,
+    derivative_filter: Option<f32>,
+    feed_forward: f32,
+
+    /// Accumulated integral term
+    integral: f32,
+    /// Filtered derivative term
+    derivative: f32,
+    last_measurement: Option<f32>,
+}
+
+fn limit(val: f32, limit: Option<f32>) -> f32 {
+    match limit {
+        Some(limit) => val.clamp(-limit.abs(), limit.abs()),
+        None => val,
+    }
+}
+
+impl PidController {
+    pub fn new(conf: &config::PidCurve) -> Self {
+        let tracking_gain =
+            conf.tracking_gain
+                .unwrap_or(if conf.p != 0.0 { conf.i / conf.p } else { 1.0 });
+        Self {
+            target: conf.target,
+            kp: conf.p,
+            ki: conf.i,
+            kd: conf.d,
+            direction: conf.direction,
+            output_min: conf.output_min,
+            output_max: conf.output_max,
+            p_limit: conf.p_limit,
+            i_limit: conf.i_limit,
+            d_limit: conf.d_limit,
+            anti_windup: conf.anti_windup,
+            tracking_gain,
+            derivative_filter: conf.derivative_filter,
+            feed_forward: conf.feed_forward,
+            integral: 0.0,
+            derivative: 0.0,
+            last_measurement: None,
+        }
+    }
+
+    /// Error with the sign adjusted so a positive error always increases the output
+    fn error(&self, measurement: f32) -> f32 {
+        match self.direction {
+            PidDirection::cooling => measurement - self.target,
+            PidDirection::heating => self.target - measurement,
+        }
+    }
+
+    /// Calculates the next output. `dt` is the time in seconds since the last update
+    pub fn update(&mut self, measurement: f32, dt: f32) -> PidOutput {
+        let error = self.error(measurement);
+        let p = limit(self.kp * error, self.p_limit);
+
+        // Derivative on measurement to prevent kicks on target changes
+        if let (Some(last), true) = (self.last_measurement, dt > 0.0) {
+            let slope = match self.direction {
+                PidDirection::cooling => measurement - last,
+                PidDirection::heating => last - measurement,
+            } / dt;
+            let raw = self.kd * slope;
+            self.derivative = match self.derivative_filter {
+                Some(time_constant) if time_constant > 0.0 => {
+                    let alpha = dt / (time_constant + dt);
+                    self.derivative + alpha * (raw - self.derivative)
+                }
+                _ => raw,
+            };
+        }
+        self.last_measurement = Some(measurement);
+        let d = limit(self.derivative, self.d_limit);
+
+        let integral = limit(self.integral + self.ki * error * dt, self.i_limit);
+        let unsaturated = p + integral + d + self.feed_forward;
+        let output = unsaturated.clamp(self.output_min, self.output_max);
+
+        self.integral = match self.anti_windup {
+            AntiWindup::none => integral,
+            AntiWindup::clamping => {
+                let saturated_high = unsaturated > self.output_max && error > 0.0;
+                let saturated_low = unsaturated < self.output_min && error < 0.0;
+                if saturated_high || saturated_low {
+                    self.integral
+                } else {
+                    integral
+                }
+            }
+            AntiWindup::back_calculation => limit(
+                integral + self.tracking_gain * (output - unsaturated) * dt,
+                self.i_limit,
+            ),
+        };
+
+        PidOutput {
+            output,
+            p,
+            i: self.integral,
+            d,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use more_asserts::{assert_gt, assert_lt};
+
+    use super::*;
+
+    fn controller(conf: config::PidCurve) -> PidController {
+        PidController::new(&conf)
+    }
+
+    #[test]
+    fn test_pid_direction() {
+        let mut cooling = controller(config::PidCurve {
+            target: 50.0,
+            p: 1.0,
+            ..Default::default()
+        });
+        assert_eq!(cooling.update(60.0, 1.0).output, 10.0);
+        assert_eq!(cooling.update(40.0, 1.0).output, 0.0);
+
+        let mut heating = controller(config::PidCurve {
+            target: 50.0,
+            p: 1.0,
+            ..Default::default()
+        });
+        heating.direction = PidDirection::heating;
+        assert_eq!(heating.update(60.0, 1.0).output, 0.0);
+        assert_eq!(heating.update(40.0, 1.0).output, 10.0);
+    }
+
+    #[test]
+    fn test_pid_limits() {
+        let mut pid = controller(config::PidCurve {
+            target: 0.0,
+            p: 10.0,
+            output_min: 20.0,
+            output_max: 80.0,
+            p_limit: Some(30.0),
+            feed_forward: 5.0,
+            ..Default::default()
+        });
+        // Feed forward is applied on top of the limited p term
+        assert_eq!(pid.update(1.0, 1.0).output, 20.0);
+        assert_eq!(pid.update(2.0, 1.0).output, 25.0);
+        let output = pid.update(100.0, 1.0);
+        assert_eq!(output.p, 30.0);
+        assert_eq!(output.output, 35.0);
+
+        pid.p_limit = None;
+        assert_eq!(pid.update(100.0, 1.0).output, 80.0);
+    }
+
+    #[test]
+    fn test_pid_integral_dt() {
+        let mut pid = controller(config::PidCurve {
+            target: 0.0,
+            i: 1.0,
+            ..Default::default()
+        });
+        assert_eq!(pid.update(10.0, 0.5).output, 5.0);
+        assert_eq!(pid.update(10.0, 2.0).output, 25.0);
+        pid.i_limit = Some(30.0);
+        assert_eq!(pid.update(10.0, 2.0).output, 30.0);
+    }
+
+    #[test]
+    fn test_pid_anti_windup() {
+        let conf = config::PidCurve {
+            target: 0.0,
+            p: 1.0,
+            i: 1.0,
+            output_max: 50.0,
+            ..Default::default()
+        };
+        let mut none = controller(conf.clone());
+        none.anti_windup = AntiWindup::none;
+        let mut clamping = controller(conf.clone());
+        let mut back_calculation = controller(conf);
+        back_calculation.anti_windup = AntiWindup::back_calculation;
+
+        // Saturate for a long time
+        for _ in 0..20 {
+            none.update(40.0, 1.0);
+            clamping.update(40.0, 1.0);
+            back_calculation.update(40.0, 1.0);
+        }
+        assert_gt!(none.integral, 500.0);
+        assert_eq!(clamping.integral, 0.0);
+        assert_lt!(back_calculation.integral, 50.0);
+
+        // Measurement drops below target. Only the wound up controller stays saturated
+        assert_eq!(none.update(-10.0, 1.0).output, 50.0);
+        assert_eq!(clamping.update(-10.0, 1.0).output, 0.0);
+        assert_lt!(back_calculation.update(-10.0, 1.0).output, 50.0);
+    }
+
+    #[test]
+    fn test_pid_derivative() {
+        let mut pid = controller(config::PidCurve {
+            target: 50.0,
+            d: 1.0,
+            ..Default::default()
+        });
+        // No derivative without a previous measurement
+        assert_eq!(pid.update(40.0, 1.0).output, 0.0);
+        // Target changes don't cause a kick
+        pid.target = 0.0;
+        assert_eq!(pid.update(40.0, 1.0).output, 0.0);
+        assert_eq!(pid.update(50.0, 2.0).output, 5.0);
+
+        let mut filtered = controller(config::PidCurve {
+            target: 50.0,
+            d: 1.0,
+            derivative_filter: Some(1.0),
+            ..Default::default()
+        });
+        filtered.update(40.0, 1.0);
+        assert_eq!(filtered.update(60.0, 1.0).output, 10.0);
+        assert_eq!(filtered.update(60.0, 1.0).output, 5.0);
+    }
+}
@@ -14,13 +14,13 @@ pub type TempSensorContainer = Arc<Mutex<TempSensor>>;
 
 pub struct TempSensor {
     pub id: String,
-    pub sensor: Box<dyn temp::TempSensor>,
+    pub sensor: Box<dyn temp::TempSensor + Send>,
     pub last_val: i32,
 }
 
 #[cfg_attr(test, automock)]
 impl TempSensor {
-    pub fn new(conf: &SensorConfig, sensor: Box<dyn temp::TempSensor>) -> Self {
+    pub fn new(conf: &SensorConfig, sensor: Box<dyn temp::TempSensor + Send>) -> Self {
         Self {
             id: conf.id.clone(),
             sensor,